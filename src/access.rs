@@ -0,0 +1,423 @@
+use crate::metadata::read_acl;
+use nix::unistd::{getegid, geteuid, getgroups};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Permission bits as `access(2)` takes them
+pub const READ: u32 = 4;
+pub const WRITE: u32 = 2;
+pub const SEARCH: u32 = 1;
+
+/// ACL entry tags, see `acl(5)`
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Version of the `system.posix_acl_access` xattr format
+const ACL_XATTR_VERSION: u32 = 2;
+
+/// Whether the current user may access `path` with all of `bits`
+///
+/// The kernel decides through `faccessat(AT_EACCESS)`, which takes the
+/// effective IDs, supplementary groups and ACLs into account. Where
+/// `AT_EACCESS` is not supported the same rules are evaluated here instead.
+pub fn user_can(path: &Path, bits: u32) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+
+    let result = unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            bits as libc::c_int,
+            libc::AT_EACCESS,
+        )
+    };
+    if result == 0 {
+        return Ok(true);
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EROFS) | Some(libc::ETXTBSY) => Ok(false),
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+            let permissions = FilePermissions::read(path)?;
+            Ok(permissions.permits(&Credentials::current()?, bits))
+        }
+        _ => Err(e),
+    }
+}
+
+/// Who is asking for access
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// The effective user and group IDs of this process
+    pub fn current() -> io::Result<Credentials> {
+        Ok(Credentials {
+            uid: geteuid().as_raw(),
+            gid: getegid().as_raw(),
+            groups: getgroups()
+                .map_err(io::Error::from)?
+                .into_iter()
+                .map(|gid| gid.as_raw())
+                .collect(),
+        })
+    }
+
+    /// Whether `gid` is the primary or a supplementary group
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// One entry of a POSIX ACL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    /// User or group ID of `ACL_USER` and `ACL_GROUP` entries
+    pub id: u32,
+}
+
+/// Formatted like `getfacl -n`, e.g. `user:1000:rw-`
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, qualifier) = match self.tag {
+            ACL_USER_OBJ => ("user", None),
+            ACL_USER => ("user", Some(self.id)),
+            ACL_GROUP_OBJ => ("group", None),
+            ACL_GROUP => ("group", Some(self.id)),
+            ACL_MASK => ("mask", None),
+            _ => ("other", None),
+        };
+        let perm = u32::from(self.perm);
+        write!(
+            f,
+            "{}:{}:{}{}{}",
+            kind,
+            qualifier.map(|id| id.to_string()).unwrap_or_default(),
+            if perm & READ != 0 { 'r' } else { '-' },
+            if perm & WRITE != 0 { 'w' } else { '-' },
+            if perm & SEARCH != 0 { 'x' } else { '-' }
+        )
+    }
+}
+
+/// Owner, mode and ACL of a file
+#[derive(Debug, Clone)]
+pub struct FilePermissions {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    /// Empty when the file has no extended ACL
+    pub acl: Vec<AclEntry>,
+}
+
+impl FilePermissions {
+    pub fn read(path: &Path) -> io::Result<FilePermissions> {
+        let metadata = fs::metadata(path)?;
+        let acl = match read_acl(path)? {
+            Some(raw) => parse_acl(&raw)?,
+            None => Vec::new(),
+        };
+        Ok(FilePermissions {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode() & 0o7777,
+            acl,
+        })
+    }
+
+    /// Whether `who` is granted all of `bits`, following the POSIX.1e
+    /// access check algorithm
+    ///
+    /// The first class that matches decides: owner, named user, then the
+    /// owning and named groups (any of which may grant), then other. Named
+    /// users and all groups are limited by the mask entry.
+    pub fn permits(&self, who: &Credentials, bits: u32) -> bool {
+        let bits = bits & 0o7;
+
+        if who.uid == 0 {
+            // Root may read and write anything, and search or execute
+            // anything that someone may search or execute
+            return bits & SEARCH == 0 || self.mode & 0o111 != 0;
+        }
+
+        if who.uid == self.uid {
+            return (self.mode >> 6) & bits == bits;
+        }
+
+        if self.acl.is_empty() {
+            if who.in_group(self.gid) {
+                return (self.mode >> 3) & bits == bits;
+            }
+            return self.mode & bits == bits;
+        }
+
+        let mask = self
+            .acl
+            .iter()
+            .find(|entry| entry.tag == ACL_MASK)
+            .map_or(0o7, |entry| u32::from(entry.perm));
+
+        if let Some(entry) = self
+            .acl
+            .iter()
+            .find(|entry| entry.tag == ACL_USER && entry.id == who.uid)
+        {
+            return u32::from(entry.perm) & mask & bits == bits;
+        }
+
+        let mut group_matched = false;
+        for entry in &self.acl {
+            let matches = match entry.tag {
+                ACL_GROUP_OBJ => who.in_group(self.gid),
+                ACL_GROUP => who.in_group(entry.id),
+                _ => false,
+            };
+            if matches {
+                if u32::from(entry.perm) & mask & bits == bits {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        self.acl
+            .iter()
+            .find(|entry| entry.tag == ACL_OTHER)
+            .map_or(self.mode & bits == bits, |entry| {
+                u32::from(entry.perm) & bits == bits
+            })
+    }
+}
+
+/// Decode the `system.posix_acl_access` xattr: a little-endian version
+/// followed by 8-byte tag, permission and ID entries
+pub fn parse_acl(raw: &[u8]) -> io::Result<Vec<AclEntry>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed ACL");
+
+    if raw.len() < 4 || !(raw.len() - 4).is_multiple_of(8) {
+        return Err(invalid());
+    }
+    let version = u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]);
+    if version != ACL_XATTR_VERSION {
+        return Err(invalid());
+    }
+
+    let entries = raw[4..]
+        .chunks_exact(8)
+        .map(|chunk| AclEntry {
+            tag: u16::from_le_bytes([chunk[0], chunk[1]]),
+            perm: u16::from_le_bytes([chunk[2], chunk[3]]),
+            id: u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+        })
+        .collect::<Vec<_>>();
+
+    let known = [
+        ACL_USER_OBJ,
+        ACL_USER,
+        ACL_GROUP_OBJ,
+        ACL_GROUP,
+        ACL_MASK,
+        ACL_OTHER,
+    ];
+    if entries.iter().any(|entry| !known.contains(&entry.tag)) {
+        return Err(invalid());
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn user(uid: u32, gid: u32, groups: &[u32]) -> Credentials {
+        Credentials {
+            uid,
+            gid,
+            groups: groups.to_vec(),
+        }
+    }
+
+    fn entry(tag: u16, perm: u32, id: u32) -> AclEntry {
+        AclEntry {
+            tag,
+            perm: perm as u16,
+            id,
+        }
+    }
+
+    fn file(mode: u32, acl: Vec<AclEntry>) -> FilePermissions {
+        FilePermissions {
+            uid: 1000,
+            gid: 100,
+            mode,
+            acl,
+        }
+    }
+
+    fn encode(entries: &[AclEntry]) -> Vec<u8> {
+        let mut raw = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for entry in entries {
+            raw.extend_from_slice(&entry.tag.to_le_bytes());
+            raw.extend_from_slice(&entry.perm.to_le_bytes());
+            raw.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        raw
+    }
+
+    #[test]
+    fn mode_bits_pick_the_first_matching_class() {
+        let plain = file(0o640, Vec::new());
+        let cases = [
+            (user(1000, 1000, &[]), READ | WRITE, true),
+            (user(1000, 100, &[]), SEARCH, false),
+            (user(1001, 100, &[]), READ, true),
+            (user(1001, 100, &[]), WRITE, false),
+            (user(1001, 1001, &[100]), READ, true),
+            (user(1001, 1001, &[5, 6]), READ, false),
+        ];
+        for (who, bits, expected) in cases {
+            assert_eq!(plain.permits(&who, bits), expected, "{:?} {}", who, bits);
+        }
+
+        // The owner class decides even when it grants less than the others
+        let owner_locked_out = file(0o077, Vec::new());
+        assert!(!owner_locked_out.permits(&user(1000, 100, &[]), READ));
+        assert!(owner_locked_out.permits(&user(1001, 1001, &[]), READ | WRITE));
+    }
+
+    #[test]
+    fn root_needs_an_execute_bit_only_to_search() {
+        let root = user(0, 0, &[]);
+        assert!(file(0o000, Vec::new()).permits(&root, READ | WRITE));
+        assert!(!file(0o600, Vec::new()).permits(&root, SEARCH));
+        assert!(file(0o001, Vec::new()).permits(&root, SEARCH));
+    }
+
+    #[test]
+    fn acl_entries_are_limited_by_the_mask() {
+        let acl = vec![
+            entry(ACL_USER_OBJ, 0o6, 0),
+            entry(ACL_USER, 0o6, 2000),
+            entry(ACL_GROUP_OBJ, 0o4, 0),
+            entry(ACL_GROUP, 0o6, 300),
+            entry(ACL_MASK, 0o4, 0),
+            entry(ACL_OTHER, 0o0, 0),
+        ];
+        let masked = file(0o640, acl.clone());
+        assert!(masked.permits(&user(2000, 2000, &[]), READ));
+        assert!(!masked.permits(&user(2000, 2000, &[]), WRITE));
+        assert!(masked.permits(&user(2001, 2001, &[300]), READ));
+        assert!(!masked.permits(&user(2001, 2001, &[300]), WRITE));
+
+        let unmasked: Vec<_> = acl.into_iter().filter(|e| e.tag != ACL_MASK).collect();
+        let unmasked = file(0o660, unmasked);
+        assert!(unmasked.permits(&user(2000, 2000, &[]), READ | WRITE));
+        assert!(unmasked.permits(&user(2001, 2001, &[300]), READ | WRITE));
+    }
+
+    #[test]
+    fn acl_named_user_and_groups_shadow_other() {
+        let acl = vec![
+            entry(ACL_USER_OBJ, 0o6, 0),
+            entry(ACL_USER, 0o0, 2000),
+            entry(ACL_GROUP_OBJ, 0o0, 0),
+            entry(ACL_GROUP, 0o4, 300),
+            entry(ACL_GROUP, 0o2, 301),
+            entry(ACL_MASK, 0o7, 0),
+            entry(ACL_OTHER, 0o4, 0),
+        ];
+        let perms = file(0o674, acl);
+        let cases = [
+            // A named user entry decides, even against a generous other
+            (user(2000, 2000, &[]), READ, false),
+            // Any matching group entry may grant
+            (user(2001, 2001, &[300, 301]), READ, true),
+            (user(2001, 2001, &[300, 301]), WRITE, true),
+            // but the bits must come from a single entry
+            (user(2001, 2001, &[300, 301]), READ | WRITE, false),
+            // A matching group that grants nothing stops at the group class
+            (user(2001, 100, &[]), READ, false),
+            (user(2001, 2001, &[]), READ, true),
+            (user(2001, 2001, &[]), WRITE, false),
+        ];
+        for (who, bits, expected) in cases {
+            assert_eq!(perms.permits(&who, bits), expected, "{:?} {}", who, bits);
+        }
+    }
+
+    #[test]
+    fn parse_acl_round_trips_entries() {
+        let entries = vec![
+            entry(ACL_USER_OBJ, 0o6, u32::MAX),
+            entry(ACL_USER, 0o4, 1000),
+            entry(ACL_GROUP_OBJ, 0o4, u32::MAX),
+            entry(ACL_GROUP, 0o5, 100),
+            entry(ACL_MASK, 0o7, u32::MAX),
+            entry(ACL_OTHER, 0o0, u32::MAX),
+        ];
+        assert_eq!(parse_acl(&encode(&entries)).unwrap(), entries);
+        assert_eq!(parse_acl(&encode(&[])).unwrap(), Vec::new());
+        assert_eq!(entries[1].to_string(), "user:1000:r--");
+        assert_eq!(entries[3].to_string(), "group:100:r-x");
+        assert_eq!(entries[4].to_string(), "mask::rwx");
+    }
+
+    #[test]
+    fn parse_acl_rejects_malformed_blobs() {
+        let good = encode(&[entry(ACL_USER_OBJ, 0o6, 0)]);
+        let mut wrong_version = good.clone();
+        wrong_version[0] = 1;
+        let cases: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            vec![2, 0, 0],
+            good[..good.len() - 1].to_vec(),
+            [good.as_slice(), &[0]].concat(),
+            wrong_version,
+            encode(&[entry(0x03, 0o6, 0)]),
+            encode(&[entry(0x40, 0o6, 0)]),
+        ];
+        for raw in cases {
+            let err = parse_acl(&raw).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn user_can_asks_the_kernel() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(user_can(&path, READ).unwrap());
+        assert!(user_can(dir.path(), WRITE | SEARCH).unwrap());
+        if geteuid().is_root() {
+            assert!(!user_can(&path, SEARCH).unwrap());
+        } else {
+            assert!(user_can(&path, WRITE).unwrap());
+        }
+        assert!(user_can(&dir.path().join("missing"), READ).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::backup::{create_backup, BackupPolicy};
+use crate::metadata::{apply_metadata, FileMetadata};
+use crate::resolve::Target;
+use nix::errno::Errno;
+use nix::fcntl::{openat, renameat, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{unlinkat, UnlinkatFlags};
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process;
+
+/// Mode given to files that did not exist before
+const NEW_FILE_MODE: u32 = 0o644;
+
+/// Attempts at finding an unused temporary name before giving up
+const TEMP_ATTEMPTS: u32 = 100;
+
+/// Replace a file's content without ever exposing a partial write
+///
+/// The content goes to a temporary sibling that receives the target's owner,
+/// group, mode, ACL, SELinux context and xattrs, is fsynced, and is then renamed
+/// over the target before the directory itself is fsynced. If any step fails,
+/// setting the owner, group or mode included, the sibling is removed and the
+/// target is left as it was.
+///
+/// Everything happens relative to the target's pinned directory; the rename
+/// replaces the directory entry itself, so a link put in its place meanwhile
+/// is replaced rather than followed.
+///
+/// Returns one message per ACL, SELinux context or xattr that could not be
+/// carried over.
+pub fn replace_file(target: &Target, data: &[u8]) -> io::Result<Vec<String>> {
+    let metadata = target.metadata()?;
+    write_atomically(target.dir(), target.name(), data, metadata.as_ref())
+}
+
+/// Result of backing up and replacing a file
+#[derive(Debug)]
+pub struct WriteOutcome {
+    /// Where the previous content was saved, if a backup was made
+    pub backup: Option<PathBuf>,
+    /// One message per ACL, SELinux context or xattr that could not be
+    /// carried over
+    pub failures: Vec<String>,
+}
+
+/// Back up the current content according to `policy`, then replace the file
+pub fn write_with_backup(
+    target: &Target,
+    data: &[u8],
+    policy: &BackupPolicy,
+) -> io::Result<WriteOutcome> {
+    let backup = create_backup(target, policy)?;
+    let failures = replace_file(target, data)?;
+
+    Ok(WriteOutcome { backup, failures })
+}
+
+/// Atomically write `name` in `dir`, giving it the given metadata instead of
+/// that of whatever it replaces
+pub fn write_with_metadata(
+    dir: &File,
+    name: &OsStr,
+    data: &[u8],
+    metadata: &FileMetadata,
+) -> io::Result<Vec<String>> {
+    write_atomically(dir, name, data, Some(metadata))
+}
+
+fn write_atomically(
+    dir: &File,
+    name: &OsStr,
+    data: &[u8],
+    metadata: Option<&FileMetadata>,
+) -> io::Result<Vec<String>> {
+    let (tmp_name, tmp) = create_temp(dir, name)?;
+
+    let failures = match fill_and_rename(dir, &tmp_name, tmp, name, data, metadata) {
+        Ok(failures) => failures,
+        Err(e) => {
+            let _ = unlinkat(dir, tmp_name.as_os_str(), UnlinkatFlags::NoRemoveDir);
+            return Err(e);
+        }
+    };
+
+    dir.sync_all()?;
+    Ok(failures)
+}
+
+fn fill_and_rename(
+    dir: &File,
+    tmp_name: &OsStr,
+    mut tmp: File,
+    name: &OsStr,
+    data: &[u8],
+    metadata: Option<&FileMetadata>,
+) -> io::Result<Vec<String>> {
+    tmp.write_all(data)?;
+
+    let failures = match metadata {
+        Some(metadata) => apply_metadata(&tmp, metadata)?,
+        None => {
+            tmp.set_permissions(Permissions::from_mode(NEW_FILE_MODE))?;
+            Vec::new()
+        }
+    };
+
+    tmp.sync_all()?;
+    renameat(dir, tmp_name, dir, name)?;
+    Ok(failures)
+}
+
+/// Create `.<name>.doasedit-<pid>-<n>` in `dir` for writing
+///
+/// `O_EXCL` and `O_NOFOLLOW` make sure the file is a new one, whatever
+/// already exists under the name.
+fn create_temp(dir: &File, name: &OsStr) -> io::Result<(OsString, File)> {
+    let flags =
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+
+    for attempt in 0..TEMP_ATTEMPTS {
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(format!(".doasedit-{}-{}", process::id(), attempt));
+
+        match openat(
+            dir,
+            tmp_name.as_os_str(),
+            flags,
+            Mode::from_bits_truncate(0o600),
+        ) {
+            Ok(fd) => return Ok((tmp_name, File::from(fd))),
+            Err(Errno::EEXIST) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        "no unused temporary file name",
+    ))
+}
@@ -0,0 +1,145 @@
+use crate::error::{backend_unavailable, unknown_backend, Result};
+use crate::utils::find_in_path;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::process::Command;
+
+/// A way of running commands as root
+pub trait PrivilegeBackend {
+    /// Name used on the command line and in the config file
+    fn name(&self) -> &'static str;
+
+    /// Build a command that runs `program` with `args` as root
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command;
+
+    /// Check whether this backend can be used on the current system
+    fn probe(&self) -> bool {
+        find_in_path(self.name()).is_some()
+    }
+
+    /// Whether the backend asks for the password on its standard input, so
+    /// the helper has to talk over other descriptors
+    fn authenticates_on_stdin(&self) -> bool {
+        false
+    }
+}
+
+pub struct Doas;
+
+impl PrivilegeBackend for Doas {
+    fn name(&self) -> &'static str {
+        "doas"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut cmd = Command::new("doas");
+        cmd.arg("--").arg(program).args(args);
+        cmd
+    }
+}
+
+pub struct Sudo;
+
+impl PrivilegeBackend for Sudo {
+    fn name(&self) -> &'static str {
+        "sudo"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("--").arg(program).args(args);
+        cmd
+    }
+}
+
+pub struct Run0;
+
+impl PrivilegeBackend for Run0 {
+    fn name(&self) -> &'static str {
+        "run0"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        let mut cmd = Command::new("run0");
+        cmd.arg("--").arg(program).args(args);
+        cmd
+    }
+}
+
+pub struct Su;
+
+impl PrivilegeBackend for Su {
+    fn name(&self) -> &'static str {
+        "su"
+    }
+
+    fn command(&self, program: &OsStr, args: &[&OsStr]) -> Command {
+        // su only takes a single shell command string, so quote every word
+        let mut script = shell_quote(program);
+        for arg in args {
+            script.push(" ");
+            script.push(shell_quote(arg));
+        }
+
+        let mut cmd = Command::new("su");
+        cmd.arg("root").arg("-c").arg(script);
+        cmd
+    }
+
+    /// su takes the password through PAM on stdin, and refuses to run at all
+    /// when stdin is not a terminal
+    fn authenticates_on_stdin(&self) -> bool {
+        true
+    }
+}
+
+/// Backends in the order they are tried when none is configured
+const AUTO_ORDER: [&str; 4] = ["doas", "sudo", "run0", "su"];
+
+/// Look up a backend by name
+pub fn backend_by_name(name: &str) -> Result<Box<dyn PrivilegeBackend>> {
+    match name {
+        "doas" => Ok(Box::new(Doas)),
+        "sudo" => Ok(Box::new(Sudo)),
+        "run0" => Ok(Box::new(Run0)),
+        "su" => Ok(Box::new(Su)),
+        _ => Err(unknown_backend(name)),
+    }
+}
+
+/// Pick the backend to use and make sure it is available
+///
+/// An explicitly requested backend (flag or config) must pass its probe;
+/// otherwise the first available backend in `AUTO_ORDER` is used.
+pub fn select_backend(requested: Option<&str>) -> Result<Box<dyn PrivilegeBackend>> {
+    if let Some(name) = requested {
+        let backend = backend_by_name(name)?;
+        if !backend.probe() {
+            return Err(backend_unavailable(name));
+        }
+        return Ok(backend);
+    }
+
+    for name in AUTO_ORDER {
+        let backend = backend_by_name(name)?;
+        if backend.probe() {
+            return Ok(backend);
+        }
+    }
+
+    Err(backend_unavailable(AUTO_ORDER[0]))
+}
+
+/// Quote a word for a POSIX shell
+fn shell_quote(word: &OsStr) -> OsString {
+    let mut quoted = vec![b'\''];
+    for &byte in word.as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    OsString::from_vec(quoted)
+}
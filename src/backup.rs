@@ -0,0 +1,265 @@
+use crate::access::{user_can, SEARCH, WRITE};
+use crate::atomic::write_with_metadata;
+use crate::resolve::{fd_path, open_dir, Target};
+use nix::dir::Dir;
+use nix::fcntl::{AtFlags, OFlag, AT_FDCWD};
+use nix::sys::stat::{fstatat, Mode};
+use nix::unistd::{unlinkat, UnlinkatFlags};
+use std::ffi::{OsStr, OsString};
+use std::fs::{DirBuilder, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default location of central backups
+pub const DEFAULT_BACKUP_DIR: &str = "/var/backups/doasedit";
+
+/// Where the previous content of a file is saved before it is replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupMode {
+    None,
+    /// `file~`, replaced on every write
+    Simple,
+    /// `file.~N~` next to the file
+    Numbered,
+    /// `file.~N~` under a root-owned directory mirroring the full path
+    Directory,
+}
+
+impl BackupMode {
+    pub fn parse(name: &str) -> Option<BackupMode> {
+        match name {
+            "none" => Some(BackupMode::None),
+            "simple" => Some(BackupMode::Simple),
+            "numbered" => Some(BackupMode::Numbered),
+            "directory" => Some(BackupMode::Directory),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            BackupMode::None => 0,
+            BackupMode::Simple => 1,
+            BackupMode::Numbered => 2,
+            BackupMode::Directory => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<BackupMode> {
+        match value {
+            0 => Some(BackupMode::None),
+            1 => Some(BackupMode::Simple),
+            2 => Some(BackupMode::Numbered),
+            3 => Some(BackupMode::Directory),
+            _ => None,
+        }
+    }
+}
+
+/// How backups are made and how long numbered backups are kept
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    pub mode: BackupMode,
+    pub dir: PathBuf,
+    /// Keep at most this many numbered backups per file (0 = no limit)
+    pub keep: u32,
+    /// Remove numbered backups older than this many days (0 = no limit)
+    pub max_age_days: u32,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        BackupPolicy {
+            mode: BackupMode::None,
+            dir: PathBuf::from(DEFAULT_BACKUP_DIR),
+            keep: 0,
+            max_age_days: 0,
+        }
+    }
+}
+
+/// Save the current content of `target` according to the policy
+///
+/// The backup keeps the owner, mode and other metadata of the original.
+/// Backups next to the file are made relative to the target's pinned
+/// directory. Returns the backup path, or `None` if there was nothing to
+/// back up.
+pub fn create_backup(target: &Target, policy: &BackupPolicy) -> io::Result<Option<PathBuf>> {
+    if policy.mode == BackupMode::None {
+        return Ok(None);
+    }
+
+    let metadata = match target.metadata()? {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    let content = match target.read()? {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+
+    let name = target.name();
+    let parent = target.path().parent().unwrap_or(Path::new("/"));
+
+    let (dir, dir_path, mirror);
+    match policy.mode {
+        BackupMode::None => return Ok(None),
+        BackupMode::Simple | BackupMode::Numbered => {
+            dir = target.dir();
+            dir_path = parent.to_path_buf();
+        }
+        BackupMode::Directory => {
+            dir_path = mirror_dir(&policy.dir, parent)?;
+            mirror = open_dir(AT_FDCWD, &dir_path)?;
+            dir = &mirror;
+        }
+    }
+
+    let backup_name = if policy.mode == BackupMode::Simple {
+        let mut backup_name = name.to_os_string();
+        backup_name.push("~");
+        backup_name
+    } else {
+        next_numbered(dir, name)?
+    };
+
+    // A failure to carry over an ACL or xattr is not worth refusing the edit
+    // for, the backup is still a faithful copy of the content; without the
+    // original owner and mode it could expose it, so those are required
+    write_with_metadata(dir, &backup_name, &content, &metadata)?;
+
+    if policy.mode != BackupMode::Simple {
+        prune_backups(dir, name, policy)?;
+    }
+
+    Ok(Some(dir_path.join(backup_name)))
+}
+
+/// Whether the current user can make the backup `policy` asks for without
+/// root: read the target and create files next to it
+///
+/// Central backups go to a root-owned tree, so they always take root.
+pub fn user_can_back_up(target: &Target, policy: &BackupPolicy) -> io::Result<bool> {
+    match policy.mode {
+        BackupMode::None => Ok(true),
+        BackupMode::Directory => Ok(false),
+        BackupMode::Simple | BackupMode::Numbered => {
+            let file = match target.open() {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(false),
+                Err(e) => return Err(e),
+            };
+            Ok(file.is_some() && user_can(&fd_path(target.dir()), WRITE | SEARCH)?)
+        }
+    }
+}
+
+/// Create the root-owned mirror of the target's directory under `base`
+fn mirror_dir(base: &Path, parent: &Path) -> io::Result<PathBuf> {
+    let relative = parent.strip_prefix("/").unwrap_or(parent);
+    let mirror = base.join(relative);
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&mirror)?;
+
+    Ok(mirror)
+}
+
+/// All numbered backups of `name` in `dir`, oldest number first
+fn numbered_backups(dir: &File, name: &OsStr) -> io::Result<Vec<(u32, OsString)>> {
+    let prefix = [name.as_bytes(), b".~"].concat();
+    let mut backups = Vec::new();
+
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    let mut entries = Dir::openat(dir, ".", flags, Mode::empty())?;
+    for entry in entries.iter() {
+        let entry = entry?;
+        let file_name = entry.file_name().to_bytes();
+        let number = file_name
+            .strip_prefix(prefix.as_slice())
+            .and_then(|rest| rest.strip_suffix(b"~"))
+            .and_then(|number| str::from_utf8(number).ok())
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            backups.push((number, OsStr::from_bytes(file_name).to_os_string()));
+        }
+    }
+
+    backups.sort();
+    Ok(backups)
+}
+
+fn next_numbered(dir: &File, name: &OsStr) -> io::Result<OsString> {
+    let next = numbered_backups(dir, name)?
+        .last()
+        .map(|(number, _)| number + 1)
+        .unwrap_or(1);
+
+    let mut backup_name = OsString::from(name);
+    backup_name.push(format!(".~{}~", next));
+    Ok(backup_name)
+}
+
+/// Apply the count and age limits, never removing the newest backup
+fn prune_backups(dir: &File, name: &OsStr, policy: &BackupPolicy) -> io::Result<()> {
+    let mut backups = numbered_backups(dir, name)?;
+    backups.pop();
+
+    let keep = policy.keep as usize;
+    if keep > 0 && backups.len() >= keep {
+        let excess = backups.len() + 1 - keep;
+        for (_, backup_name) in backups.drain(..excess) {
+            unlinkat(dir, backup_name.as_os_str(), UnlinkatFlags::NoRemoveDir)?;
+        }
+    }
+
+    if policy.max_age_days > 0 {
+        let max_age = Duration::from_secs(u64::from(policy.max_age_days) * 24 * 60 * 60);
+        let now = SystemTime::now();
+        for (_, backup_name) in backups {
+            let stat = fstatat(dir, backup_name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW)?;
+            let modified = UNIX_EPOCH + Duration::from_secs(stat.st_mtime.max(0) as u64);
+            if now.duration_since(modified).unwrap_or_default() > max_age {
+                unlinkat(dir, backup_name.as_os_str(), UnlinkatFlags::NoRemoveDir)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbered_backups_match_the_name_byte_for_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let names: [&[u8]; 6] = [
+            b"conf\xff.~2~",
+            b"conf\xff.~10~",
+            b"conf\xfe.~3~",
+            b"conf\xff.~x~",
+            b"conf\xff.~4",
+            b"conf\xff",
+        ];
+        for name in names {
+            File::create(dir.path().join(OsStr::from_bytes(name))).unwrap();
+        }
+
+        let backups = numbered_backups(
+            &File::open(dir.path()).unwrap(),
+            OsStr::from_bytes(b"conf\xff"),
+        )
+        .unwrap();
+        let numbers: Vec<_> = backups.iter().map(|(number, _)| *number).collect();
+        assert_eq!(numbers, [2, 10]);
+        assert_eq!(backups[1].1, OsStr::from_bytes(b"conf\xff.~10~"));
+    }
+}
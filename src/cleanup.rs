@@ -0,0 +1,133 @@
+use nix::errno::Errno;
+use nix::sys::signal::kill;
+use nix::unistd::{getuid, Pid};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use tempfile::Builder;
+
+/// Name prefix of session directories, followed by the owning process ID
+const SESSION_PREFIX: &str = "doasedit-";
+
+/// Session directories that still have to be wiped
+static ACTIVE_DIRS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// The private temporary directory of one run
+///
+/// Every file in it is overwritten before it is removed, whether the run ends
+/// normally, with an error, a panic or a signal (see `signals`).
+pub struct SessionDir {
+    path: PathBuf,
+}
+
+impl SessionDir {
+    pub fn create() -> io::Result<SessionDir> {
+        let path = Builder::new()
+            .prefix(&format!("{}{}-", SESSION_PREFIX, process::id()))
+            .tempdir()?
+            .keep();
+
+        lock_active_dirs().push(path.clone());
+        Ok(SessionDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        wipe_and_remove(&self.path);
+        lock_active_dirs().retain(|path| path != &self.path);
+    }
+}
+
+/// A poisoned lock only means a panic elsewhere; the list itself is still fine
+fn lock_active_dirs() -> std::sync::MutexGuard<'static, Vec<PathBuf>> {
+    ACTIVE_DIRS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Wipe every session directory that has not been dropped yet
+pub fn wipe_active_dirs() {
+    for path in lock_active_dirs().drain(..) {
+        wipe_and_remove(&path);
+    }
+}
+
+/// Overwrite every file under `dir` with zeros, then remove the whole tree
+///
+/// Errors are ignored so that as much as possible is wiped.
+pub fn wipe_and_remove(dir: &Path) {
+    wipe_tree(dir);
+    let _ = fs::remove_dir_all(dir);
+}
+
+fn wipe_tree(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        // Never follow symlinks out of the directory
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => wipe_tree(&path),
+            Ok(metadata) if metadata.is_file() => {
+                let _ = overwrite_file(&path, metadata.len());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn overwrite_file(path: &Path, len: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = [0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()
+}
+
+/// Remove session directories left behind by runs that were killed
+///
+/// A directory is stale when it belongs to the current user and the process
+/// named in it no longer exists.
+pub fn sweep_stale_dirs() {
+    let entries = match fs::read_dir(env::temp_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let uid = getuid().as_raw();
+
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let pid = match name
+            .to_str()
+            .and_then(|name| name.strip_prefix(SESSION_PREFIX))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse::<i32>().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+
+        let path = entry.path();
+        let owned_dir = fs::symlink_metadata(&path)
+            .is_ok_and(|metadata| metadata.is_dir() && metadata.uid() == uid);
+        if owned_dir && kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH) {
+            wipe_and_remove(&path);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::backup::BackupMode;
+use crate::error::{config_error, Result};
+use crate::resolve::SymlinkPolicy;
+use crate::utils::split_shell_words;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// System-wide configuration file
+pub const CONFIG_PATH: &str = "/etc/doasedit.conf";
+
+/// Settings read from the system configuration file
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub backend: Option<String>,
+    pub backup: Option<BackupMode>,
+    pub backup_dir: Option<PathBuf>,
+    pub backup_keep: Option<u32>,
+    pub backup_max_age: Option<u32>,
+    pub review: Option<bool>,
+    /// Open all files in one editor session; unset means decide by editor
+    pub together: Option<bool>,
+    pub symlinks: Option<SymlinkPolicy>,
+    /// Path globs mapped to validation command templates, in file order
+    pub validators: Vec<(String, Vec<String>)>,
+}
+
+/// Load the system configuration, or the defaults if there is none
+///
+/// The file is only honoured when it is owned by root and not writable by
+/// anyone else, since it decides which commands are run as root.
+pub fn load_config() -> Result<Config> {
+    let path = Path::new(CONFIG_PATH);
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(Config::default()),
+    };
+
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+        return Err(config_error(
+            CONFIG_PATH,
+            0,
+            "must be owned by root and not writable by group or others",
+        ));
+    }
+
+    parse_config(&fs::read_to_string(path)?)
+}
+
+/// Parse `key = value` lines; `#` at the start of a line or after
+/// whitespace, outside quotes, starts a comment
+///
+/// Lines after a `[validators]` header map a path glob to a command:
+/// `/etc/nginx/** = nginx -t -c {}`.
+pub fn parse_config(content: &str) -> Result<Config> {
+    let mut config = Config::default();
+    let mut in_validators = false;
+
+    for (index, raw_line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let line = strip_comment(raw_line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if section.trim() != "validators" {
+                return Err(config_error(
+                    CONFIG_PATH,
+                    line_number,
+                    &format!("unknown section '{}'", section.trim()),
+                ));
+            }
+            in_validators = true;
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| config_error(CONFIG_PATH, line_number, "expected 'key = value'"))?;
+
+        if in_validators {
+            config
+                .validators
+                .push((key.to_string(), parse_command(value, line_number)?));
+            continue;
+        }
+
+        match key {
+            "backend" => config.backend = Some(value.to_string()),
+            "backup" => {
+                config.backup = Some(BackupMode::parse(value).ok_or_else(|| {
+                    config_error(
+                        CONFIG_PATH,
+                        line_number,
+                        "backup must be none, simple, numbered or directory",
+                    )
+                })?)
+            }
+            "backup_dir" => {
+                if !value.starts_with('/') {
+                    return Err(config_error(
+                        CONFIG_PATH,
+                        line_number,
+                        "backup_dir must be an absolute path",
+                    ));
+                }
+                config.backup_dir = Some(PathBuf::from(value));
+            }
+            "backup_keep" => config.backup_keep = Some(parse_number(value, line_number)?),
+            "backup_max_age" => config.backup_max_age = Some(parse_number(value, line_number)?),
+            "review" => config.review = Some(parse_bool(value, line_number)?),
+            "together" => config.together = Some(parse_bool(value, line_number)?),
+            "symlinks" => {
+                config.symlinks = Some(SymlinkPolicy::parse(value).ok_or_else(|| {
+                    config_error(
+                        CONFIG_PATH,
+                        line_number,
+                        "symlinks must be refuse, follow or replace-link",
+                    )
+                })?)
+            }
+            _ => {
+                return Err(config_error(
+                    CONFIG_PATH,
+                    line_number,
+                    &format!("unknown setting '{}'", key),
+                ))
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+/// `line` without its comment
+///
+/// Quotes and backslashes are read as in validator commands, so
+/// `sh -c 'x # y'` and `a\#b` keep their `#`.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => escaped = true,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') if previous.is_whitespace() => return &line[..index],
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+fn parse_number(value: &str, line_number: usize) -> Result<u32> {
+    value.parse().map_err(|_| {
+        config_error(
+            CONFIG_PATH,
+            line_number,
+            &format!("'{}' is not a number", value),
+        )
+    })
+}
+
+fn parse_bool(value: &str, line_number: usize) -> Result<bool> {
+    match value {
+        "yes" | "true" | "on" => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _ => Err(config_error(
+            CONFIG_PATH,
+            line_number,
+            &format!("'{}' is not yes or no", value),
+        )),
+    }
+}
+
+/// Split a validator command into shell words; it must mention the file as `{}`
+fn parse_command(value: &str, line_number: usize) -> Result<Vec<String>> {
+    let words = split_shell_words(value)
+        .ok_or_else(|| config_error(CONFIG_PATH, line_number, "unbalanced quotes"))?;
+
+    if words.is_empty() {
+        return Err(config_error(CONFIG_PATH, line_number, "empty command"));
+    }
+    if !words.iter().any(|word| word.contains("{}")) {
+        return Err(config_error(
+            CONFIG_PATH,
+            line_number,
+            "command must contain {} for the file to check",
+        ));
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DoaseditError;
+
+    /// The line a bad configuration is reported on
+    fn error_line(content: &str) -> usize {
+        match parse_config(content) {
+            Err(DoaseditError::Config { line, .. }) => line,
+            other => panic!("{:?} parsed as {:?}", content, other),
+        }
+    }
+
+    #[test]
+    fn parses_settings() {
+        let config = parse_config(
+            "\
+backend = sudo
+backup = numbered
+backup_dir = /var/backups/doasedit
+backup_keep = 5
+backup_max_age = 30
+review = yes
+together = off
+symlinks = replace-link
+",
+        )
+        .unwrap();
+        assert_eq!(config.backend.as_deref(), Some("sudo"));
+        assert!(matches!(config.backup, Some(BackupMode::Numbered)));
+        assert_eq!(
+            config.backup_dir.as_deref(),
+            Some(Path::new("/var/backups/doasedit"))
+        );
+        assert_eq!(config.backup_keep, Some(5));
+        assert_eq!(config.backup_max_age, Some(30));
+        assert_eq!(config.review, Some(true));
+        assert_eq!(config.together, Some(false));
+        assert!(matches!(config.symlinks, Some(SymlinkPolicy::ReplaceLink)));
+        assert!(config.validators.is_empty());
+    }
+
+    #[test]
+    fn parses_validators_in_order() {
+        let config = parse_config(
+            "\
+review = no
+[validators]
+/etc/nginx/** = nginx -t -c {}
+/etc/foo = sh -c 'foo-check \"$1\" # strict' - {}
+",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validators,
+            [
+                (
+                    "/etc/nginx/**".to_string(),
+                    vec!["nginx", "-t", "-c", "{}"]
+                        .into_iter()
+                        .map(String::from)
+                        .collect::<Vec<_>>()
+                ),
+                (
+                    "/etc/foo".to_string(),
+                    vec!["sh", "-c", "foo-check \"$1\" # strict", "-", "{}"]
+                        .into_iter()
+                        .map(String::from)
+                        .collect()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn strips_comments() {
+        let cases = [
+            ("# comment", ""),
+            ("review = yes # comment", "review = yes "),
+            ("review = yes\t# comment", "review = yes\t"),
+            ("backup_dir = /srv/a#b", "backup_dir = /srv/a#b"),
+            ("x = sh -c 'x # y' {}", "x = sh -c 'x # y' {}"),
+            ("x = sh -c \"x # y\" {} # z", "x = sh -c \"x # y\" {} "),
+            ("x = a \\# {}", "x = a \\# {}"),
+            ("x = 'it''s' # z", "x = 'it''s' "),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(strip_comment(line), expected, "{:?}", line);
+        }
+
+        let config = parse_config("# settings\n\nreview = yes # for now\n").unwrap();
+        assert_eq!(config.review, Some(true));
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        assert_eq!(error_line("review = yes\nbogus"), 2);
+        assert_eq!(error_line("# c\n\nnope = 1"), 3);
+        assert_eq!(error_line("backup = sometimes"), 1);
+        assert_eq!(error_line("backup_dir = relative"), 1);
+        assert_eq!(error_line("backup_keep = many"), 1);
+        assert_eq!(error_line("review = maybe"), 1);
+        assert_eq!(error_line("symlinks = sometimes"), 1);
+        assert_eq!(error_line("[other]"), 1);
+        assert_eq!(error_line("[validators]\n/etc/x = check"), 2);
+        assert_eq!(error_line("[validators]\n/etc/x = 'check {}"), 2);
+        assert_eq!(error_line("[validators]\n/etc/x ="), 2);
+    }
+}
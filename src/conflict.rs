@@ -0,0 +1,114 @@
+use crate::diff::{merge3, Merge};
+use crate::error::{user_abort, Result};
+use crate::utils::read_user_input;
+use std::fs;
+use std::path::Path;
+use std::str;
+
+/// How a change made to the target while it was being edited was handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrentChange {
+    /// The target still matches the snapshot
+    None,
+    /// The edited file now holds a clean merge of both changes
+    Merged,
+    /// The edited file now holds conflict markers to resolve in the editor
+    EditConflicts,
+    /// Overwrite the other change with the edited file
+    KeepMine,
+    /// Leave the target as the other party left it
+    KeepTheirs,
+}
+
+/// Compare the target's current content with the snapshot taken before editing
+///
+/// On a mismatch the user chooses between a three-way merge, keeping their own
+/// version, keeping the current one, or aborting; files that are not UTF-8
+/// text are never merged. When merging, the snapshot
+/// is moved forward to the current content so the merged result is compared
+/// against what is now on disk.
+pub fn check_concurrent_change(
+    label: &str,
+    current: &[u8],
+    snapshot_path: &Path,
+    edited_path: &Path,
+) -> Result<ConcurrentChange> {
+    let snapshot = fs::read(snapshot_path)?;
+    if snapshot == current {
+        return Ok(ConcurrentChange::None);
+    }
+
+    let merge = merge_text(&snapshot, &fs::read(edited_path)?, current, label);
+
+    let prompt = match &merge {
+        Some(merge) if merge.conflicts == 0 => {
+            eprintln!(
+                "doasedit: {} was changed by someone else while you were editing; \
+                 their changes merge cleanly with yours.",
+                label
+            );
+            "(U)se merged result, keep (M)ine, keep (T)heirs, (A)bort: [U/m/t/a]? "
+        }
+        Some(merge) => {
+            eprintln!(
+                "doasedit: {} was changed by someone else while you were editing; \
+                 {} region(s) conflict with your changes.",
+                label, merge.conflicts
+            );
+            "(E)dit conflicts, keep (M)ine, keep (T)heirs, (A)bort: [E/m/t/a]? "
+        }
+        None => {
+            eprintln!(
+                "doasedit: {} was changed by someone else while you were editing; \
+                 it is not UTF-8 text, so the changes cannot be merged.",
+                label
+            );
+            "Keep (M)ine, keep (T)heirs, (A)bort: [m/t/a]? "
+        }
+    };
+    loop {
+        let input = read_user_input(prompt)?;
+
+        let (merge, outcome) = match (input.trim().to_lowercase().as_str(), &merge) {
+            ("m", _) => return Ok(ConcurrentChange::KeepMine),
+            ("t", _) => return Ok(ConcurrentChange::KeepTheirs),
+            ("a", _) => return Err(user_abort()),
+            ("" | "u", Some(merge)) if merge.conflicts == 0 => (merge, ConcurrentChange::Merged),
+            ("" | "e", Some(merge)) if merge.conflicts > 0 => {
+                (merge, ConcurrentChange::EditConflicts)
+            }
+            _ => continue,
+        };
+        fs::write(edited_path, &merge.text)?;
+        fs::write(snapshot_path, current)?;
+        return Ok(outcome);
+    }
+}
+
+/// Merge both changes line by line, or `None` if any version is not UTF-8
+/// text, which a line merge would mangle
+fn merge_text(base: &[u8], ours: &[u8], theirs: &[u8], label: &str) -> Option<Merge> {
+    Some(merge3(
+        str::from_utf8(base).ok()?,
+        str::from_utf8(ours).ok()?,
+        str::from_utf8(theirs).ok()?,
+        "yours",
+        &format!("current {}", label),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_only_text() {
+        let merge = merge_text(b"a\nb\nc\n", b"A\nb\nc\n", b"a\nb\nC\n", "hosts").unwrap();
+        assert_eq!((merge.text.as_str(), merge.conflicts), ("A\nb\nC\n", 0));
+
+        let binary = b"a\n\xff\nc\n";
+        assert!(merge_text(binary, b"A\n\xff\nc\n", b"a\n\xff\nC\n", "hosts").is_none());
+        assert!(merge_text(b"a\n", b"\xfe\n", b"b\n", "hosts").is_none());
+        assert!(merge_text(b"a\n", b"b\n", b"\xfe\n", "hosts").is_none());
+    }
+}
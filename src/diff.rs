@@ -0,0 +1,458 @@
+/// Lines of unchanged context shown around each hunk
+const CONTEXT_LINES: usize = 3;
+
+/// Most diagonal entries kept across all Myers snapshots (about 64 MiB)
+///
+/// The trace grows with edit distance times file length; past this the diff
+/// gives up and reports the whole file as replaced.
+const MAX_TRACE_CELLS: usize = 8 << 20;
+
+const COLOR_HEADER: &str = "\x1b[1m";
+const COLOR_HUNK: &str = "\x1b[36m";
+const COLOR_DELETE: &str = "\x1b[31m";
+const COLOR_INSERT: &str = "\x1b[32m";
+const COLOR_RESET: &str = "\x1b[0m";
+
+/// One step of an edit script turning `old` into `new`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffOp {
+    /// `old[i]` and `new[j]` are the same line
+    Equal(usize, usize),
+    /// `old[i]` was removed
+    Delete(usize),
+    /// `new[j]` was added
+    Insert(usize),
+}
+
+/// Split text into lines, keeping the line terminators
+pub fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// Compute a shortest edit script with Myers' algorithm
+///
+/// Falls back to deleting every old line and inserting every new one when
+/// the search would need more than `MAX_TRACE_CELLS` of memory.
+pub fn diff_lines(old: &[&str], new: &[&str]) -> Vec<DiffOp> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let max = (n + m) as usize;
+    let offset = max as isize;
+
+    // v[k] is the furthest x reached on diagonal k; one snapshot per step d
+    let mut v = vec![0isize; 2 * max + 2];
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    'search: for d in 0..=max as isize {
+        if (trace.len() + 1).saturating_mul(v.len()) > MAX_TRACE_CELLS {
+            return replace_all(old.len(), new.len());
+        }
+        trace.push(v.clone());
+        let mut k = -d;
+        while k <= d {
+            let index = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+                v[index + 1]
+            } else {
+                v[index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    // Walk the snapshots backwards to recover the path
+    let mut ops = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let k = x - y;
+        let index = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[index - 1] < v[index + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = if d == 0 {
+            0
+        } else {
+            v[(prev_k + offset) as usize]
+        };
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x.max(0) && y > prev_y.max(0) {
+            x -= 1;
+            y -= 1;
+            ops.push(DiffOp::Equal(x as usize, y as usize));
+        }
+        if d == 0 {
+            break;
+        }
+        if x == prev_x {
+            y -= 1;
+            ops.push(DiffOp::Insert(y as usize));
+        } else {
+            x -= 1;
+            ops.push(DiffOp::Delete(x as usize));
+        }
+    }
+
+    ops.reverse();
+    ops
+}
+
+/// Edit script that swaps out every line, used when the real diff is too big
+fn replace_all(old_len: usize, new_len: usize) -> Vec<DiffOp> {
+    (0..old_len)
+        .map(DiffOp::Delete)
+        .chain((0..new_len).map(DiffOp::Insert))
+        .collect()
+}
+
+/// Render a unified diff, optionally with ANSI colours
+pub fn unified_diff(
+    old_label: &str,
+    new_label: &str,
+    old_text: &str,
+    new_text: &str,
+    color: bool,
+) -> String {
+    let old = split_lines(old_text);
+    let new = split_lines(new_text);
+    let ops = diff_lines(&old, &new);
+
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("{}{}{}", code, text, COLOR_RESET)
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut out = String::new();
+    out.push_str(&paint(COLOR_HEADER, &format!("--- {}", old_label)));
+    out.push('\n');
+    out.push_str(&paint(COLOR_HEADER, &format!("+++ {}", new_label)));
+    out.push('\n');
+
+    for (start, end) in hunk_ranges(&ops) {
+        let hunk = &ops[start..end];
+        let (old_start, old_count, new_start, new_count) = hunk_position(hunk, &ops[..start]);
+        out.push_str(&paint(
+            COLOR_HUNK,
+            &format!(
+                "@@ -{},{} +{},{} @@",
+                old_start, old_count, new_start, new_count
+            ),
+        ));
+        out.push('\n');
+
+        for op in hunk {
+            let (prefix, line, code) = match *op {
+                DiffOp::Equal(i, _) => (' ', old[i], ""),
+                DiffOp::Delete(i) => ('-', old[i], COLOR_DELETE),
+                DiffOp::Insert(j) => ('+', new[j], COLOR_INSERT),
+            };
+            let text = format!("{}{}", prefix, line.trim_end_matches('\n'));
+            if code.is_empty() {
+                out.push_str(&text);
+            } else {
+                out.push_str(&paint(code, &text));
+            }
+            out.push('\n');
+            if !line.ends_with('\n') {
+                out.push_str("\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    out
+}
+
+/// Group changes into hunks with surrounding context, as `ops` index ranges
+fn hunk_ranges(ops: &[DiffOp]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+
+    for (index, op) in ops.iter().enumerate() {
+        if matches!(op, DiffOp::Equal(..)) {
+            continue;
+        }
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + 1 + CONTEXT_LINES).min(ops.len());
+        match ranges.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+
+    ranges
+}
+
+/// 1-based start lines and line counts of a hunk, as shown in `@@` headers
+fn hunk_position(hunk: &[DiffOp], before: &[DiffOp]) -> (usize, usize, usize, usize) {
+    let old_before = before
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Insert(_)))
+        .count();
+    let new_before = before
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Delete(_)))
+        .count();
+    let old_count = hunk
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Insert(_)))
+        .count();
+    let new_count = hunk
+        .iter()
+        .filter(|op| !matches!(op, DiffOp::Delete(_)))
+        .count();
+
+    // An empty side is reported at the line before it, as diff(1) does
+    let old_start = if old_count == 0 {
+        old_before
+    } else {
+        old_before + 1
+    };
+    let new_start = if new_count == 0 {
+        new_before
+    } else {
+        new_before + 1
+    };
+
+    (old_start, old_count, new_start, new_count)
+}
+
+/// Result of a three-way merge
+#[derive(Debug)]
+pub struct Merge {
+    pub text: String,
+    /// Number of regions wrapped in conflict markers
+    pub conflicts: usize,
+}
+
+/// Merge two sets of changes made to the same base text
+///
+/// Regions changed on only one side take that side; regions changed the same
+/// way on both sides are taken once; anything else becomes a conflict marked
+/// in diff3 style with the given labels.
+pub fn merge3(
+    base_text: &str,
+    ours_text: &str,
+    theirs_text: &str,
+    ours_label: &str,
+    theirs_label: &str,
+) -> Merge {
+    let base = split_lines(base_text);
+    let ours = split_lines(ours_text);
+    let theirs = split_lines(theirs_text);
+
+    let ours_match = base_matches(base.len(), &diff_lines(&base, &ours));
+    let theirs_match = base_matches(base.len(), &diff_lines(&base, &theirs));
+
+    // Base lines kept by both sides are the points where all three agree
+    let mut sync_points: Vec<(usize, usize, usize)> = (0..base.len())
+        .filter_map(|i| Some((i, ours_match[i]?, theirs_match[i]?)))
+        .collect();
+    sync_points.push((base.len(), ours.len(), theirs.len()));
+
+    let mut text = String::new();
+    let mut conflicts = 0;
+    let (mut base_pos, mut ours_pos, mut theirs_pos) = (0, 0, 0);
+
+    for (base_sync, ours_sync, theirs_sync) in sync_points {
+        let base_chunk = &base[base_pos..base_sync];
+        let ours_chunk = &ours[ours_pos..ours_sync];
+        let theirs_chunk = &theirs[theirs_pos..theirs_sync];
+
+        if ours_chunk == base_chunk {
+            push_lines(&mut text, theirs_chunk);
+        } else if theirs_chunk == base_chunk || ours_chunk == theirs_chunk {
+            push_lines(&mut text, ours_chunk);
+        } else {
+            conflicts += 1;
+            push_marker(&mut text, &format!("<<<<<<< {}", ours_label));
+            push_lines(&mut text, ours_chunk);
+            push_marker(&mut text, "||||||| original");
+            push_lines(&mut text, base_chunk);
+            push_marker(&mut text, "=======");
+            push_lines(&mut text, theirs_chunk);
+            push_marker(&mut text, &format!(">>>>>>> {}", theirs_label));
+        }
+
+        if base_sync < base.len() {
+            text.push_str(base[base_sync]);
+        }
+        base_pos = base_sync + 1;
+        ours_pos = ours_sync + 1;
+        theirs_pos = theirs_sync + 1;
+    }
+
+    Merge { text, conflicts }
+}
+
+/// For each base line, the index of the same line on the other side, if kept
+fn base_matches(base_len: usize, ops: &[DiffOp]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base_len];
+    for op in ops {
+        if let DiffOp::Equal(i, j) = *op {
+            matches[i] = Some(j);
+        }
+    }
+    matches
+}
+
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+}
+
+/// Conflict markers always start on a line of their own
+fn push_marker(text: &mut String, marker: &str) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    text.push_str(marker);
+    text.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rebuild `new` from `old` by following the edit script
+    fn apply(old: &[&str], new: &[&str], ops: &[DiffOp]) -> String {
+        let (mut i, mut j) = (0, 0);
+        let mut out = String::new();
+        for op in ops {
+            match *op {
+                DiffOp::Equal(a, b) => {
+                    assert_eq!((a, b), (i, j));
+                    assert_eq!(old[a], new[b]);
+                    out.push_str(new[b]);
+                    i += 1;
+                    j += 1;
+                }
+                DiffOp::Delete(a) => {
+                    assert_eq!(a, i);
+                    i += 1;
+                }
+                DiffOp::Insert(b) => {
+                    assert_eq!(b, j);
+                    out.push_str(new[b]);
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!((i, j), (old.len(), new.len()));
+        out
+    }
+
+    fn edits(ops: &[DiffOp]) -> usize {
+        ops.iter()
+            .filter(|op| !matches!(op, DiffOp::Equal(..)))
+            .count()
+    }
+
+    #[test]
+    fn diff_lines_produces_shortest_scripts() {
+        let cases: &[(&str, &str, usize)] = &[
+            ("", "", 0),
+            ("a\n", "a\n", 0),
+            ("", "a\nb\n", 2),
+            ("a\nb\n", "", 2),
+            ("a\nb\nc\n", "a\nc\n", 1),
+            ("a\nc\n", "a\nb\nc\n", 1),
+            ("a\nb\nc\n", "a\nx\nc\n", 2),
+            ("a\nb\nc\na\nb\nb\na\n", "c\nb\na\nb\na\nc\n", 5),
+            ("a\nb", "a\nb\n", 2),
+        ];
+        for &(old_text, new_text, expected) in cases {
+            let old = split_lines(old_text);
+            let new = split_lines(new_text);
+            let ops = diff_lines(&old, &new);
+            assert_eq!(apply(&old, &new, &ops), new_text, "{:?}", old_text);
+            assert_eq!(edits(&ops), expected, "{:?} -> {:?}", old_text, new_text);
+        }
+    }
+
+    #[test]
+    fn diff_lines_replaces_everything_past_the_memory_cap() {
+        let old_text: String = (0..3000).map(|i| format!("old {}\n", i)).collect();
+        let new_text: String = (0..3000).map(|i| format!("new {}\n", i)).collect();
+        let old = split_lines(&old_text);
+        let new = split_lines(&new_text);
+        let ops = diff_lines(&old, &new);
+        assert_eq!(ops, replace_all(old.len(), new.len()));
+        assert_eq!(apply(&old, &new, &ops), new_text);
+    }
+
+    #[test]
+    fn unified_diff_reports_hunk_positions() {
+        let diff = unified_diff("a", "b", "1\n2\n3\n", "1\n3\n4\n", false);
+        assert_eq!(diff, "--- a\n+++ b\n@@ -1,3 +1,3 @@\n 1\n-2\n 3\n+4\n");
+        let diff = unified_diff("a", "b", "", "x", false);
+        assert_eq!(
+            diff,
+            "--- a\n+++ b\n@@ -0,0 +1,1 @@\n+x\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn merge3_takes_changes_from_either_side() {
+        let base = "a\nb\nc\nd\ne\n";
+        let ours = "A\nb\nc\nd\ne\n";
+        let theirs = "a\nb\nc\nd\nE\n";
+        let merge = merge3(base, ours, theirs, "ours", "theirs");
+        assert_eq!(merge.conflicts, 0);
+        assert_eq!(merge.text, "A\nb\nc\nd\nE\n");
+    }
+
+    #[test]
+    fn merge3_takes_identical_changes_once() {
+        let merge = merge3("a\nb\nc\n", "a\nx\nc\n", "a\nx\nc\n", "ours", "theirs");
+        assert_eq!(merge.conflicts, 0);
+        assert_eq!(merge.text, "a\nx\nc\n");
+    }
+
+    #[test]
+    fn merge3_handles_insertions_and_deletions() {
+        let merge = merge3(
+            "a\nb\nc\nd\n",
+            "a\nnew\nb\nc\nd\n",
+            "a\nb\nc\n",
+            "ours",
+            "theirs",
+        );
+        assert_eq!(merge.conflicts, 0);
+        assert_eq!(merge.text, "a\nnew\nb\nc\n");
+    }
+
+    #[test]
+    fn merge3_marks_conflicting_changes() {
+        let merge = merge3("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", "ours", "theirs");
+        assert_eq!(merge.conflicts, 1);
+        assert_eq!(
+            merge.text,
+            "a\n<<<<<<< ours\nx\n||||||| original\nb\n=======\ny\n>>>>>>> theirs\nc\n"
+        );
+    }
+
+    #[test]
+    fn merge3_puts_markers_on_their_own_line() {
+        let merge = merge3("a", "b", "c", "ours", "theirs");
+        assert_eq!(merge.conflicts, 1);
+        assert_eq!(
+            merge.text,
+            "<<<<<<< ours\nb\n||||||| original\na\n=======\nc\n>>>>>>> theirs\n"
+        );
+    }
+}
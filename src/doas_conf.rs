@@ -0,0 +1,911 @@
+use crate::lockout::Caller;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+/// The main configuration file
+pub const DOAS_CONF: &str = "/etc/doas.conf";
+
+/// Fragments read after the main file, in order of their names
+pub const DOAS_FRAGMENT_DIR: &str = "/etc/doas.d";
+
+/// What a rule does for the commands it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Permit,
+    Deny,
+}
+
+/// Who a rule applies to: a user, or the members of a group (`:group`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    User(String),
+    Group(String),
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::User(name) => write!(f, "{}", name),
+            Identity::Group(name) => write!(f, ":{}", name),
+        }
+    }
+}
+
+/// Options of a `permit` rule
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub nopass: bool,
+    pub nolog: bool,
+    pub persist: bool,
+    pub keepenv: bool,
+    /// The words of `setenv { ... }`, if given
+    pub setenv: Option<Vec<String>>,
+}
+
+/// One line of doas.conf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// The file the rule comes from, if not the one being checked
+    pub file: Option<PathBuf>,
+    /// Line the rule starts on, counting from 1
+    pub line: usize,
+    pub action: Action,
+    pub options: Options,
+    pub identity: Identity,
+    /// `as` target; any user if `None`
+    pub target: Option<String>,
+    /// `cmd`; any command if `None`
+    pub cmd: Option<String>,
+    /// `args`; any arguments if `None`, none at all if empty
+    pub args: Option<Vec<String>>,
+}
+
+impl Rule {
+    /// Whether every request this rule matches is also matched by `other`
+    ///
+    /// doas applies the last matching rule, so a rule covered by a later one
+    /// never takes effect.
+    fn covered_by(&self, other: &Rule, accounts: &Accounts) -> bool {
+        let identity = match (&self.identity, &other.identity) {
+            (a, b) if a == b => true,
+            (Identity::User(user), Identity::Group(group)) => accounts.in_group(user, group),
+            _ => false,
+        };
+        let target = other.target.is_none() || other.target == self.target;
+        let cmd = other.cmd.is_none()
+            || (other.cmd == self.cmd && (other.args.is_none() || other.args == self.args));
+
+        identity && target && cmd
+    }
+
+    fn runs_as_root(&self) -> bool {
+        matches!(self.target.as_deref(), None | Some("root") | Some("0"))
+    }
+
+    /// Whether the rule matches `caller` running `command` as root
+    fn matches(&self, caller: &Caller, command: &[String]) -> bool {
+        let identity = match &self.identity {
+            Identity::User(user) => caller.is_user(user),
+            Identity::Group(group) => caller.in_group(group),
+        };
+        let cmd = match (&self.cmd, command.split_first()) {
+            (None, _) => true,
+            (Some(cmd), Some((program, args))) => {
+                cmd == program && self.args.as_deref().is_none_or(|rule| rule == args)
+            }
+            (Some(_), None) => false,
+        };
+
+        identity && self.runs_as_root() && cmd
+    }
+}
+
+/// Whether doas would let `caller` run `command` as root
+///
+/// As in doas itself, the last matching rule decides and nothing is
+/// permitted without one.
+pub fn permits_root(rules: &[Rule], caller: &Caller, command: &[String]) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| rule.matches(caller, command))
+        .is_some_and(|rule| rule.action == Action::Permit)
+}
+
+/// Whether `caller` may run anything at all as root
+///
+/// Every command a rule for the caller names is tried, and a shell for rules
+/// that do not name one.
+pub fn grants_root(rules: &[Rule], caller: &Caller) -> bool {
+    rules.iter().any(|rule| {
+        let command = match &rule.cmd {
+            Some(cmd) => {
+                let mut command = vec![cmd.clone()];
+                command.extend(rule.args.iter().flatten().cloned());
+                command
+            }
+            None => vec!["/bin/sh".to_string()],
+        };
+        rule.action == Action::Permit && permits_root(rules, caller, &command)
+    })
+}
+
+/// A syntax error, with where it was found
+#[derive(Debug)]
+pub struct ParseError {
+    /// The file, if not the one being checked
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", location(&self.file, self.line), self.message)
+    }
+}
+
+/// A valid but risky or useless construct
+#[derive(Debug)]
+pub struct Warning {
+    /// The file, if not the one being checked
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", location(&self.file, self.line), self.message)
+    }
+}
+
+/// `line N`, preceded by the file if it is not the one being checked
+fn location(file: &Option<PathBuf>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{} line {}", file.display(), line),
+        None => format!("line {}", line),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    /// A word, and whether any of it was quoted or escaped, which keeps it
+    /// from being read as a keyword
+    Word(String, bool),
+    LeftBrace,
+    RightBrace,
+}
+
+impl Token {
+    fn keyword(&self) -> Option<&str> {
+        match self {
+            Token::Word(word, false) => Some(word),
+            _ => None,
+        }
+    }
+}
+
+/// Split doas.conf into lines of tokens, each with the line it starts on
+///
+/// Follows doas's own lexer: `#` starts a comment, a backslash escapes the
+/// next character or joins the next line, double quotes group words, and
+/// unquoted `{` and `}` stand on their own.
+fn tokenize(content: &str) -> Result<Vec<(usize, Vec<Token>)>, ParseError> {
+    let mut lines = Vec::new();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut start = 1;
+    let mut chars = content.chars().peekable();
+
+    loop {
+        // Skip blanks, comments and line continuations between tokens
+        match chars.peek() {
+            Some(' ' | '\t' | '\r') => {
+                chars.next();
+                continue;
+            }
+            Some('#') => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+                continue;
+            }
+            Some('\\') => {
+                let mut ahead = chars.clone();
+                ahead.next();
+                if ahead.peek() == Some(&'\n') {
+                    chars = ahead;
+                    chars.next();
+                    line += 1;
+                    continue;
+                }
+            }
+            Some('\n') | None => {
+                if !tokens.is_empty() {
+                    lines.push((start, std::mem::take(&mut tokens)));
+                }
+                if chars.next().is_none() {
+                    return Ok(lines);
+                }
+                line += 1;
+                continue;
+            }
+            Some('{') => {
+                chars.next();
+                push_token(&mut tokens, &mut start, line, Token::LeftBrace);
+                continue;
+            }
+            Some('}') => {
+                chars.next();
+                push_token(&mut tokens, &mut start, line, Token::RightBrace);
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        let word_line = line;
+        let mut word = String::new();
+        let mut literal = false;
+        let mut quoted = false;
+        loop {
+            match chars.peek().copied() {
+                None | Some('\n') if quoted => {
+                    return Err(ParseError {
+                        file: None,
+                        line,
+                        message: "unterminated quotes".to_string(),
+                    })
+                }
+                None => break,
+                Some(' ' | '\t' | '\r' | '\n' | '#' | '{' | '}') if !quoted => break,
+                Some('"') => {
+                    chars.next();
+                    quoted = !quoted;
+                    literal = true;
+                }
+                Some('\\') => {
+                    chars.next();
+                    match chars.next() {
+                        Some('\n') => line += 1,
+                        Some(c) => {
+                            word.push(c);
+                            literal = true;
+                        }
+                        None => {
+                            return Err(ParseError {
+                                file: None,
+                                line,
+                                message: "unterminated escape".to_string(),
+                            })
+                        }
+                    }
+                }
+                Some(c) => {
+                    chars.next();
+                    word.push(c);
+                }
+            }
+        }
+        push_token(
+            &mut tokens,
+            &mut start,
+            word_line,
+            Token::Word(word, literal),
+        );
+    }
+}
+
+fn push_token(tokens: &mut Vec<Token>, start: &mut usize, line: usize, token: Token) {
+    if tokens.is_empty() {
+        *start = line;
+    }
+    tokens.push(token);
+}
+
+/// Parse doas.conf into its rules, in order
+///
+/// The grammar is that of doas.conf(5):
+/// `permit|deny [options] identity [as target] [cmd command [args ...]]`,
+/// where options are `nopass`, `nolog`, `persist`, `keepenv` and
+/// `setenv { ... }`, and only `permit` takes options.
+pub fn parse(content: &str) -> Result<Vec<Rule>, ParseError> {
+    tokenize(content)?
+        .into_iter()
+        .map(|(line, tokens)| {
+            parse_rule(line, &tokens).map_err(|message| ParseError {
+                file: None,
+                line,
+                message,
+            })
+        })
+        .collect()
+}
+
+/// Parse several files as one configuration, in order
+///
+/// Each file is given with its path, or `None` for the one being checked;
+/// rules and errors are marked with the file they come from.
+pub fn parse_files(files: &[(Option<PathBuf>, String)]) -> Result<Vec<Rule>, ParseError> {
+    let mut rules = Vec::new();
+    for (file, content) in files {
+        let parsed = parse(content).map_err(|e| ParseError {
+            file: file.clone(),
+            ..e
+        })?;
+        rules.extend(parsed.into_iter().map(|rule| Rule {
+            file: file.clone(),
+            ..rule
+        }));
+    }
+    Ok(rules)
+}
+
+fn parse_rule(line: usize, tokens: &[Token]) -> Result<Rule, String> {
+    let mut tokens = tokens.iter().peekable();
+
+    let action = match tokens.next().and_then(Token::keyword) {
+        Some("permit") => Action::Permit,
+        Some("deny") => Action::Deny,
+        _ => return Err("expected permit or deny".to_string()),
+    };
+
+    let mut options = Options::default();
+    while let Some(keyword) = tokens.peek().and_then(|token| token.keyword()) {
+        let flag = match keyword {
+            "nopass" => &mut options.nopass,
+            "nolog" => &mut options.nolog,
+            "persist" => &mut options.persist,
+            "keepenv" => &mut options.keepenv,
+            "setenv" => {
+                tokens.next();
+                if options.setenv.is_some() {
+                    return Err("setenv given twice".to_string());
+                }
+                if tokens.next() != Some(&Token::LeftBrace) {
+                    return Err("expected { after setenv".to_string());
+                }
+                let mut words = Vec::new();
+                loop {
+                    match tokens.next() {
+                        Some(Token::Word(word, _)) => words.push(word.clone()),
+                        Some(Token::RightBrace) => break,
+                        _ => return Err("missing } after setenv".to_string()),
+                    }
+                }
+                options.setenv = Some(words);
+                continue;
+            }
+            _ => break,
+        };
+        if *flag {
+            return Err(format!("{} given twice", keyword));
+        }
+        *flag = true;
+        tokens.next();
+    }
+
+    if action == Action::Deny && options != Options::default() {
+        return Err("deny rules take no options".to_string());
+    }
+    if options.nopass && options.persist {
+        return Err("nopass and persist cannot be combined".to_string());
+    }
+
+    let identity = match tokens.next() {
+        Some(Token::Word(word, _)) => match word.strip_prefix(':') {
+            Some("") => return Err("missing group name".to_string()),
+            Some(group) => Identity::Group(group.to_string()),
+            None => Identity::User(word.clone()),
+        },
+        _ => return Err("missing user or group".to_string()),
+    };
+
+    let mut rule = Rule {
+        file: None,
+        line,
+        action,
+        options,
+        identity,
+        target: None,
+        cmd: None,
+        args: None,
+    };
+
+    if tokens
+        .next_if(|token| token.keyword() == Some("as"))
+        .is_some()
+    {
+        rule.target = Some(word(tokens.next(), "as")?);
+    }
+
+    if tokens
+        .next_if(|token| token.keyword() == Some("cmd"))
+        .is_some()
+    {
+        rule.cmd = Some(word(tokens.next(), "cmd")?);
+
+        if tokens
+            .next_if(|token| token.keyword() == Some("args"))
+            .is_some()
+        {
+            let mut args = Vec::new();
+            for token in tokens.by_ref() {
+                args.push(word(Some(token), "args")?);
+            }
+            rule.args = Some(args);
+        }
+    }
+
+    match tokens.next() {
+        None => Ok(rule),
+        Some(Token::Word(word, _)) => Err(format!("unexpected '{}'", word)),
+        Some(_) => Err("unexpected brace".to_string()),
+    }
+}
+
+fn word(token: Option<&Token>, after: &str) -> Result<String, String> {
+    match token {
+        Some(Token::Word(word, _)) => Ok(word.clone()),
+        _ => Err(format!("expected a word after {}", after)),
+    }
+}
+
+/// Users and groups from `/etc/passwd` and `/etc/group`
+#[derive(Debug, Default)]
+pub struct Accounts {
+    /// User name to uid and primary gid
+    users: HashMap<String, (u32, u32)>,
+    /// Group name to gid and listed members
+    groups: HashMap<String, (u32, Vec<String>)>,
+}
+
+impl Accounts {
+    /// Read the local account databases, or `None` if either cannot be read
+    pub fn read() -> Option<Accounts> {
+        let passwd = fs::read_to_string("/etc/passwd").ok()?;
+        let group = fs::read_to_string("/etc/group").ok()?;
+        Some(Accounts::parse(&passwd, &group))
+    }
+
+    fn parse(passwd: &str, group: &str) -> Accounts {
+        let mut accounts = Accounts::default();
+        for fields in passwd
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+        {
+            if let [name, _, uid, gid, ..] = fields[..] {
+                if let (Ok(uid), Ok(gid)) = (uid.parse(), gid.parse()) {
+                    accounts.users.insert(name.to_string(), (uid, gid));
+                }
+            }
+        }
+        for fields in group
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+        {
+            if let [name, _, gid, members, ..] = fields[..] {
+                if let Ok(gid) = gid.parse() {
+                    let members = members
+                        .split(',')
+                        .filter(|member| !member.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                    accounts.groups.insert(name.to_string(), (gid, members));
+                }
+            }
+        }
+        accounts
+    }
+
+    /// doas also accepts numeric ids
+    fn has_user(&self, name: &str) -> bool {
+        self.users.contains_key(name)
+            || name
+                .parse::<u32>()
+                .is_ok_and(|uid| self.users.values().any(|&(id, _)| id == uid))
+    }
+
+    fn has_group(&self, name: &str) -> bool {
+        self.groups.contains_key(name)
+            || name
+                .parse::<u32>()
+                .is_ok_and(|gid| self.groups.values().any(|&(id, _)| id == gid))
+    }
+
+    fn in_group(&self, user: &str, group: &str) -> bool {
+        let Some((gid, members)) = self.groups.get(group) else {
+            return false;
+        };
+        members.iter().any(|member| member == user)
+            || self
+                .users
+                .get(user)
+                .is_some_and(|&(_, primary)| primary == *gid)
+    }
+}
+
+/// Warn about rules that are valid but risky or never take effect
+///
+/// Without `accounts`, users and groups are not checked.
+pub fn lint(rules: &[Rule], accounts: Option<&Accounts>) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let no_accounts = Accounts::default();
+    let known = accounts.unwrap_or(&no_accounts);
+
+    for (index, rule) in rules.iter().enumerate() {
+        let mut warn = |message: String| {
+            warnings.push(Warning {
+                file: rule.file.clone(),
+                line: rule.line,
+                message,
+            })
+        };
+        let target = rule.target.as_deref().unwrap_or("any user");
+
+        if let Some(accounts) = accounts {
+            match &rule.identity {
+                Identity::User(user) if !accounts.has_user(user) => {
+                    warn(format!("unknown user '{}'", user))
+                }
+                Identity::Group(group) if !accounts.has_group(group) => {
+                    warn(format!("unknown group '{}'", group))
+                }
+                _ => {}
+            }
+            if let Some(target) = &rule.target {
+                if !accounts.has_user(target) {
+                    warn(format!("unknown target user '{}'", target));
+                }
+            }
+        }
+
+        if rule.action == Action::Permit {
+            if rule.options.nopass && rule.cmd.is_none() {
+                warn(format!(
+                    "{} may run any command as {} without a password; limit it with cmd",
+                    rule.identity, target
+                ));
+            }
+            if rule.options.keepenv && rule.runs_as_root() {
+                warn(format!(
+                    "keepenv passes the environment of {} to commands run as root",
+                    rule.identity
+                ));
+            }
+        }
+
+        if let Some(later) = rules[index + 1..]
+            .iter()
+            .find(|later| rule.covered_by(later, known))
+        {
+            warn(format!(
+                "never applies, the rule on {} matches everything it does",
+                location(&later.file, later.line)
+            ));
+        }
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(list: &[&str]) -> Vec<String> {
+        list.iter().map(|word| word.to_string()).collect()
+    }
+
+    fn rule(line: usize, action: Action, identity: Identity) -> Rule {
+        Rule {
+            file: None,
+            line,
+            action,
+            options: Options::default(),
+            identity,
+            target: None,
+            cmd: None,
+            args: None,
+        }
+    }
+
+    fn user(name: &str) -> Identity {
+        Identity::User(name.to_string())
+    }
+
+    fn group(name: &str) -> Identity {
+        Identity::Group(name.to_string())
+    }
+
+    fn accounts() -> Accounts {
+        Accounts::parse(
+            "root:x:0:0::/root:/bin/sh\nalice:x:1000:1000::/home/alice:/bin/sh\n\
+             bob:x:1001:100::/home/bob:/bin/sh\n",
+            "root:x:0:\nwheel:x:10:alice\nusers:x:100:\nalice:x:1000:\n",
+        )
+    }
+
+    fn messages(content: &str, accounts: Option<&Accounts>) -> Vec<(usize, String)> {
+        let rules = parse(content).unwrap();
+        lint(&rules, accounts)
+            .into_iter()
+            .map(|warning| (warning.line, warning.message))
+            .collect()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let cases = vec![
+            ("permit alice", rule(1, Action::Permit, user("alice"))),
+            ("deny :wheel", rule(1, Action::Deny, group("wheel"))),
+            (
+                "permit alice as bob",
+                Rule {
+                    target: Some("bob".to_string()),
+                    ..rule(1, Action::Permit, user("alice"))
+                },
+            ),
+            (
+                "permit alice cmd /bin/ls",
+                Rule {
+                    cmd: Some("/bin/ls".to_string()),
+                    ..rule(1, Action::Permit, user("alice"))
+                },
+            ),
+            (
+                "permit alice as root cmd /bin/ls args -l /tmp",
+                Rule {
+                    target: Some("root".to_string()),
+                    cmd: Some("/bin/ls".to_string()),
+                    args: Some(words(&["-l", "/tmp"])),
+                    ..rule(1, Action::Permit, user("alice"))
+                },
+            ),
+            (
+                "permit alice cmd /bin/ls args",
+                Rule {
+                    cmd: Some("/bin/ls".to_string()),
+                    args: Some(Vec::new()),
+                    ..rule(1, Action::Permit, user("alice"))
+                },
+            ),
+            (
+                "permit nopass nolog keepenv setenv { FOO=bar -HOME } :wheel",
+                Rule {
+                    options: Options {
+                        nopass: true,
+                        nolog: true,
+                        keepenv: true,
+                        setenv: Some(words(&["FOO=bar", "-HOME"])),
+                        ..Options::default()
+                    },
+                    ..rule(1, Action::Permit, group("wheel"))
+                },
+            ),
+            (
+                "permit persist setenv {} alice",
+                Rule {
+                    options: Options {
+                        persist: true,
+                        setenv: Some(Vec::new()),
+                        ..Options::default()
+                    },
+                    ..rule(1, Action::Permit, user("alice"))
+                },
+            ),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(parse(content).unwrap(), vec![expected], "{}", content);
+        }
+    }
+
+    #[test]
+    fn tokenizes_quotes_escapes_and_continuations() {
+        let cases = vec![
+            // Quoting keeps a keyword from being read as one
+            ("permit \"nopass\"", user("nopass"), None, None),
+            ("permit \\nopass", user("nopass"), None, None),
+            (
+                "permit alice cmd \"/opt/my tool\" args \"a b\" c\\ d",
+                user("alice"),
+                Some("/opt/my tool"),
+                Some(words(&["a b", "c d"])),
+            ),
+            (
+                "permit alice cmd /bin/echo args \"\" # comment",
+                user("alice"),
+                Some("/bin/echo"),
+                Some(words(&[""])),
+            ),
+            (
+                "permit alice \\\n  cmd /bin/ls",
+                user("alice"),
+                Some("/bin/ls"),
+                None,
+            ),
+            ("permit al\\\nice", user("alice"), None, None),
+        ];
+        for (content, identity, cmd, args) in cases {
+            let rules = parse(content).unwrap();
+            assert_eq!(rules.len(), 1, "{}", content);
+            assert_eq!(rules[0].identity, identity, "{}", content);
+            assert_eq!(rules[0].cmd.as_deref(), cmd, "{}", content);
+            assert_eq!(rules[0].args, args, "{}", content);
+        }
+    }
+
+    #[test]
+    fn numbers_rules_by_the_line_they_start_on() {
+        let content = "# comment\n\npermit alice \\\n  cmd /bin/ls\ndeny bob # trailing\n\n\
+                       permit setenv {\\\nA } :wheel\n";
+        let lines: Vec<_> = parse(content)
+            .unwrap()
+            .iter()
+            .map(|rule| rule.line)
+            .collect();
+        assert_eq!(lines, vec![3, 5, 7]);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let cases = [
+            ("allow alice", 1, "expected permit or deny"),
+            ("\"permit\" alice", 1, "expected permit or deny"),
+            ("permit", 1, "missing user or group"),
+            ("permit nopass", 1, "missing user or group"),
+            ("permit :", 1, "missing group name"),
+            ("permit nopass nopass alice", 1, "nopass given twice"),
+            (
+                "permit nopass persist alice",
+                1,
+                "nopass and persist cannot be combined",
+            ),
+            ("deny nopass alice", 1, "deny rules take no options"),
+            ("deny setenv { A } alice", 1, "deny rules take no options"),
+            ("permit setenv alice", 1, "expected { after setenv"),
+            ("permit setenv { A alice", 1, "missing } after setenv"),
+            ("permit setenv {} setenv {} alice", 1, "setenv given twice"),
+            ("permit alice as", 1, "expected a word after as"),
+            ("permit alice cmd", 1, "expected a word after cmd"),
+            (
+                "permit alice cmd ls args {",
+                1,
+                "expected a word after args",
+            ),
+            ("permit alice bob", 1, "unexpected 'bob'"),
+            ("permit alice }", 1, "unexpected brace"),
+            (
+                "permit alice\n\npermit alice cmd \"ls\n",
+                3,
+                "unterminated quotes",
+            ),
+            ("permit alice\\", 1, "unterminated escape"),
+            (
+                "permit alice\n# ok\npermit \\\n bob as",
+                3,
+                "expected a word after as",
+            ),
+        ];
+        for (content, line, message) in cases {
+            let error = parse(content).unwrap_err();
+            assert_eq!(
+                (error.line, error.message.as_str()),
+                (line, message),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn marks_rules_and_errors_with_their_file() {
+        let fragment = PathBuf::from("/etc/doas.d/10-admins.conf");
+        let files = vec![
+            (None, "permit alice\n".to_string()),
+            (Some(fragment.clone()), "\ndeny bob\n".to_string()),
+        ];
+        let rules = parse_files(&files).unwrap();
+        assert_eq!(rules[0].file, None);
+        assert_eq!(rules[1].file, Some(fragment.clone()));
+        assert_eq!(rules[1].line, 2);
+
+        let files = vec![(Some(fragment), "permit\n".to_string())];
+        let error = parse_files(&files).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "/etc/doas.d/10-admins.conf line 1: missing user or group"
+        );
+    }
+
+    #[test]
+    fn warns_about_shadowed_rules() {
+        let cases = vec![
+            ("permit alice cmd /bin/ls\npermit alice", vec![1]),
+            ("permit alice\npermit alice cmd /bin/ls", vec![]),
+            ("permit alice cmd ls args -l\npermit alice cmd ls", vec![1]),
+            ("permit alice cmd ls\npermit alice cmd ls args -l", vec![]),
+            ("permit alice as bob\ndeny alice", vec![1]),
+            ("permit alice\ndeny alice as bob", vec![]),
+            ("permit alice\npermit :wheel", vec![1]),
+            ("permit bob\npermit :users", vec![1]),
+            ("permit bob\npermit :wheel", vec![]),
+            ("permit :wheel\npermit alice", vec![]),
+        ];
+        let known = accounts();
+        for (content, lines) in cases {
+            let shadowed: Vec<_> = messages(content, Some(&known))
+                .into_iter()
+                .filter(|(_, message)| message.starts_with("never applies"))
+                .map(|(line, _)| line)
+                .collect();
+            assert_eq!(shadowed, lines, "{}", content);
+        }
+        assert_eq!(
+            messages("permit alice cmd ls\npermit alice", None),
+            vec![(
+                1,
+                "never applies, the rule on line 2 matches everything it does".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn warns_about_risky_options() {
+        let cases = vec![
+            (
+                "permit nopass alice",
+                vec!["alice may run any command as any user without a password; limit it with cmd"],
+            ),
+            (
+                "permit nopass :wheel as bob",
+                vec![":wheel may run any command as bob without a password; limit it with cmd"],
+            ),
+            ("permit nopass alice cmd /bin/ls", vec![]),
+            (
+                "permit keepenv alice",
+                vec!["keepenv passes the environment of alice to commands run as root"],
+            ),
+            (
+                "permit keepenv alice as 0",
+                vec!["keepenv passes the environment of alice to commands run as root"],
+            ),
+            ("permit keepenv alice as bob", vec![]),
+            ("permit persist alice", vec![]),
+        ];
+        for (content, expected) in cases {
+            let found: Vec<_> = messages(content, None)
+                .into_iter()
+                .map(|(_, message)| message)
+                .collect();
+            assert_eq!(found, expected, "{}", content);
+        }
+    }
+
+    #[test]
+    fn warns_about_unknown_accounts() {
+        let known = accounts();
+        let cases = vec![
+            ("permit alice", vec![]),
+            ("permit 1001", vec![]),
+            ("permit :wheel as 1000", vec![]),
+            ("permit :10", vec![]),
+            ("permit mallory", vec!["unknown user 'mallory'"]),
+            ("permit 4242", vec!["unknown user '4242'"]),
+            ("permit :staff", vec!["unknown group 'staff'"]),
+            (
+                "permit alice as nobody",
+                vec!["unknown target user 'nobody'"],
+            ),
+        ];
+        for (content, expected) in cases {
+            let found: Vec<_> = messages(content, Some(&known))
+                .into_iter()
+                .map(|(_, message)| message)
+                .collect();
+            assert_eq!(found, expected, "{}", content);
+        }
+        // Without the account databases nothing is reported
+        assert!(messages("permit mallory as nobody", None).is_empty());
+    }
+}
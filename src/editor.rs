@@ -1,57 +1,230 @@
 use crate::error::{
-    doas_cat_permission_denied, doas_unavailable, editor_error, invalid_editor,
-    no_editor_specified, user_abort, Result,
+    editor_error, editor_not_found, editor_spawn_failed, file_given_twice, no_editor_specified,
+    unbalanced_quotes, user_abort, validation_error, Result,
 };
-use crate::utils::{create_copy_filename, get_filename, read_user_input};
+use crate::helper::Helper;
+use crate::signals::{check_interrupted, run_foreground};
+use crate::utils::{can_prompt, find_in_path, mirror_path, read_user_input, split_shell_words};
+use crate::validate::Validator;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
 
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Command;
+
+/// An editor program and the arguments to pass before the file name
+#[derive(Debug, Clone)]
+pub struct EditorCommand {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+}
+
+/// Editors that accept several files at once, with the option that shows
+/// them side by side or in tabs
+const MULTI_FILE_EDITORS: [(&str, Option<&str>); 12] = [
+    ("vim", Some("-p")),
+    ("gvim", Some("-p")),
+    ("nvim", Some("-O")),
+    ("code", None),
+    ("codium", None),
+    ("subl", None),
+    ("emacs", None),
+    ("emacsclient", None),
+    ("nano", None),
+    ("micro", None),
+    ("hx", None),
+    ("kak", None),
+];
+
+/// How an editor is told to open a file at a given line
+#[derive(Clone, Copy)]
+enum LineSyntax {
+    /// `+N file`
+    Plus,
+    /// `-g file:N`
+    Goto,
+    /// `file:N`
+    Suffix,
+}
+
+/// Editors known to accept a starting line
+const LINE_EDITORS: [(&str, LineSyntax); 15] = [
+    ("vi", LineSyntax::Plus),
+    ("vim", LineSyntax::Plus),
+    ("gvim", LineSyntax::Plus),
+    ("nvim", LineSyntax::Plus),
+    ("nvi", LineSyntax::Plus),
+    ("nano", LineSyntax::Plus),
+    ("emacs", LineSyntax::Plus),
+    ("emacsclient", LineSyntax::Plus),
+    ("mg", LineSyntax::Plus),
+    ("micro", LineSyntax::Plus),
+    ("kak", LineSyntax::Plus),
+    ("code", LineSyntax::Goto),
+    ("codium", LineSyntax::Goto),
+    ("subl", LineSyntax::Suffix),
+    ("hx", LineSyntax::Suffix),
+];
+
+impl EditorCommand {
+    /// Whether the editor is known to handle several files in one session
+    pub fn supports_multiple_files(&self) -> bool {
+        self.multi_file_entry().is_some()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.program.file_name()?.to_str()
+    }
+
+    fn multi_file_entry(&self) -> Option<&(&'static str, Option<&'static str>)> {
+        let name = self.name()?;
+        MULTI_FILE_EDITORS
+            .iter()
+            .find(|(editor, _)| *editor == name)
+    }
+
+    /// The arguments opening `file` with the cursor on `line`, or just the
+    /// file for editors not known to take a line
+    fn file_at_line(&self, file: &Path, line: usize) -> Vec<OsString> {
+        let syntax = LINE_EDITORS
+            .iter()
+            .find(|(editor, _)| Some(*editor) == self.name())
+            .map(|(_, syntax)| *syntax);
+
+        let mut file_line = file.as_os_str().to_os_string();
+        file_line.push(format!(":{}", line));
+        match syntax {
+            Some(LineSyntax::Plus) => vec![format!("+{}", line).into(), file.into()],
+            Some(LineSyntax::Goto) => vec!["-g".into(), file_line],
+            Some(LineSyntax::Suffix) => vec![file_line],
+            None => vec![file.into()],
+        }
+    }
+}
 
 /// Get the editor command from environment variables or default to vi
-pub fn get_editor_command() -> Result<String> {
+///
+/// The value is split into words like a shell would, so `EDITOR="code --wait"`
+/// runs `code` with `--wait` before the file name.
+pub fn get_editor_command() -> Result<EditorCommand> {
     // Try environment variables in order: DOAS_EDITOR, VISUAL, EDITOR
     for var in ["DOAS_EDITOR", "VISUAL", "EDITOR"] {
         if let Ok(val) = env::var(var) {
-            if !val.is_empty() {
-                // Check if command exists
-                if Command::new("which")
-                    .arg(&val)
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .status()
-                    .is_ok()
-                {
-                    return Ok(val);
-                } else {
-                    return Err(no_editor_specified());
-                }
+            if !val.trim().is_empty() {
+                let words = split_shell_words(&val).ok_or_else(|| unbalanced_quotes(var, &val))?;
+                return resolve_editor(words);
             }
         }
     }
 
     // Default to vi if available
-    if Command::new("which")
-        .arg("vi")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .is_ok()
-    {
-        return Ok("vi".to_string());
+    resolve_editor(vec!["vi".to_string()])
+}
+
+/// `editor`, or the one from the environment for a run that started without
+/// one because it was not expected to need it
+pub fn editor_or_default(editor: Option<&EditorCommand>) -> Result<EditorCommand> {
+    match editor {
+        Some(editor) => Ok(editor.clone()),
+        None => get_editor_command(),
+    }
+}
+
+fn resolve_editor(mut words: Vec<String>) -> Result<EditorCommand> {
+    if words.is_empty() {
+        return Err(no_editor_specified());
     }
+    let name = words.remove(0);
+    let program = find_in_path(&name).ok_or_else(|| editor_not_found(&name))?;
 
-    Err(no_editor_specified())
+    Ok(EditorCommand {
+        program,
+        args: words,
+    })
 }
 
 /// Open a file with the specified editor
-pub fn open_file_with_editor(file_path: &Path, editor: &str) -> Result<()> {
-    let status = Command::new(editor)
-        .arg(file_path)
-        .status()
-        .map_err(|e| invalid_editor(&e.to_string()))?;
+pub fn open_file_with_editor(file_path: &Path, editor: &EditorCommand) -> Result<()> {
+    let mut command = Command::new(&editor.program);
+    command.args(&editor.args).arg(file_path);
+    run_editor(&mut command, editor)
+}
+
+/// What a validator found, shown while the file is edited again
+struct Note<'a> {
+    /// Where the sidecar file goes, for editors that can show it
+    path: &'a Path,
+    target: &'a Path,
+    heading: String,
+    messages: &'a [String],
+}
+
+/// Reopen a file that failed validation at the offending line
+///
+/// Editors that handle several files are also given the note, written out
+/// as a sidecar file, so it can be read next to the file. For other editors
+/// the note is printed instead, and the editor only starts once the user
+/// pressed Enter, since it may clear the screen.
+fn open_file_at_problem(
+    file_path: &Path,
+    line: Option<usize>,
+    note: &Note,
+    editor: &EditorCommand,
+) -> Result<()> {
+    let mut command = Command::new(&editor.program);
+    command.args(&editor.args);
+
+    let show_note = editor.supports_multiple_files();
+    if show_note {
+        write_note(note)?;
+        if let Some((_, Some(flag))) = editor.multi_file_entry() {
+            if !editor.args.iter().any(|arg| arg == flag) {
+                command.arg(flag);
+            }
+        }
+    } else {
+        // The heading names doasedit already
+        eprintln!("{}", note.heading);
+        for message in note.messages {
+            eprintln!("  {}", message);
+        }
+        read_user_input("Press Enter to edit the file again ")?;
+    }
+
+    match line {
+        Some(line) => command.args(editor.file_at_line(file_path, line)),
+        None => command.arg(file_path),
+    };
+    if show_note {
+        command.arg(note.path);
+    }
+
+    run_editor(&mut command, editor)
+}
+
+/// Open several files in a single editor session
+pub fn open_files_with_editor(file_paths: &[&Path], editor: &EditorCommand) -> Result<()> {
+    let mut command = Command::new(&editor.program);
+    command.args(&editor.args);
+
+    if file_paths.len() > 1 {
+        if let Some((_, Some(flag))) = editor.multi_file_entry() {
+            if !editor.args.iter().any(|arg| arg == flag) {
+                command.arg(flag);
+            }
+        }
+    }
+
+    command.args(file_paths);
+    run_editor(&mut command, editor)
+}
+
+fn run_editor(command: &mut Command, editor: &EditorCommand) -> Result<()> {
+    let status = run_foreground(command).map_err(|e| editor_spawn_failed(&editor.program, e))?;
+    check_interrupted()?;
 
     if status.success() {
         Ok(())
@@ -60,90 +233,293 @@ pub fn open_file_with_editor(file_path: &Path, editor: &str) -> Result<()> {
     }
 }
 
-/// Validate doas configuration file, allowing the user to fix any errors
-pub fn validate_doas_config(tmp_file_path: &Path, editor_cmd: &str) -> Result<()> {
+/// Run a validator over the edited copy of `target`, an absolute path,
+/// allowing the user to fix any errors
+///
+/// Editing again reopens the file at the line the validator reported, with
+/// the error in the sidecar file `note_path` for editors that take several
+/// files and printed for others, which start once Enter is pressed. The sidecar file is
+/// removed afterwards.
+/// Warnings about a valid file are shown too, and may be fixed the same way.
+/// Returns `true` if the user chose to overwrite despite a validation error.
+pub fn run_validator(
+    validator: &dyn Validator,
+    target: &Path,
+    tmp_file_path: &Path,
+    note_path: &Path,
+    editor_cmd: Option<&EditorCommand>,
+    helper: &mut Helper,
+) -> Result<bool> {
+    let result = validate_until_accepted(
+        validator,
+        target,
+        tmp_file_path,
+        note_path,
+        editor_cmd,
+        helper,
+    );
+    let _ = fs::remove_file(note_path);
+    result
+}
+
+fn validate_until_accepted(
+    validator: &dyn Validator,
+    target: &Path,
+    tmp_file_path: &Path,
+    note_path: &Path,
+    editor_cmd: Option<&EditorCommand>,
+    helper: &mut Helper,
+) -> Result<bool> {
     loop {
-        let output = Command::new("doas")
-            .arg("-C")
-            .arg(tmp_file_path)
-            .output()
-            .map_err(|_| doas_unavailable())?;
-
-        if output.status.success() {
-            break;
+        let problem = match validator.validate(tmp_file_path, target, helper)? {
+            None => {
+                if accept_warnings(
+                    validator,
+                    target,
+                    tmp_file_path,
+                    note_path,
+                    editor_cmd,
+                    helper,
+                )? {
+                    return Ok(false);
+                }
+                continue;
+            }
+            Some(problem) => problem,
+        };
+
+        let error = validation_error(validator.name(), &problem.message);
+        // Nobody to fix it, so the error is the outcome
+        if !can_prompt() {
+            return Err(error);
         }
+        eprintln!("doasedit: {}", error);
 
-        eprint!(
-            "doasedit: Replacing '{}' would introduce the above error and break doas.\n",
-            tmp_file_path.display()
+        eprintln!(
+            "doasedit: Replacing '{}' would introduce the above error.",
+            target.display()
         );
 
         let input = read_user_input("(E)dit again, (O)verwrite anyway, (A)bort: [E/o/a]? ")?;
 
         match input.trim().to_lowercase().as_str() {
-            "o" => break,
+            "o" => return Ok(true),
             "a" => return Err(user_abort()),
             _ => {
-                open_file_with_editor(tmp_file_path, editor_cmd)?;
+                let note = Note {
+                    path: note_path,
+                    target,
+                    heading: format!("doasedit could not validate {}:", target.display()),
+                    messages: &[error.to_string()],
+                };
+                open_file_at_problem(
+                    tmp_file_path,
+                    problem.line,
+                    &note,
+                    &editor_or_default(editor_cmd)?,
+                )?;
             }
         }
     }
-
-    Ok(())
 }
 
-/// Copy a file to a temporary location with secure permissions
-pub fn create_secure_temp_copy(file_path: &Path, temp_dir: &Path) -> Result<(PathBuf, PathBuf)> {
-    let filename = get_filename(file_path)?;
-    let copy_name = create_copy_filename(&filename);
+/// Show the validator's warnings about a valid file and let the user fix them
+///
+/// Returns `true` once the user accepts the file as it is, `false` after it
+/// was edited again and needs validating anew. Without a terminal the
+/// warnings are only shown and the file is accepted.
+fn accept_warnings(
+    validator: &dyn Validator,
+    target: &Path,
+    tmp_file_path: &Path,
+    note_path: &Path,
+    editor_cmd: Option<&EditorCommand>,
+    helper: &mut Helper,
+) -> Result<bool> {
+    let warnings = validator.lint(tmp_file_path, target, helper)?;
+    if warnings.is_empty() {
+        return Ok(true);
+    }
+
+    let messages: Vec<String> = warnings
+        .iter()
+        .map(|warning| format!("{} warning: {}", validator.name(), warning.message))
+        .collect();
+    for message in &messages {
+        eprintln!("doasedit: {}", message);
+    }
 
-    // Create temporary files paths
-    let tmp_file_path = temp_dir.join(&filename);
-    let tmp_copy_path = temp_dir.join(&copy_name);
+    // Warnings do not make a file invalid, so without a terminal to ask on
+    // the default answer stands
+    if !can_prompt() {
+        eprintln!(
+            "doasedit: {}: writing despite the warnings, no terminal to ask on",
+            target.display()
+        );
+        return Ok(true);
+    }
 
-    // Create the files
-    fs::write(&tmp_file_path, "")?;
-    fs::write(&tmp_copy_path, "")?;
+    let input = read_user_input("(C)ontinue, (E)dit again, (A)bort: [C/e/a]? ")?;
 
-    // Set secure permissions (0600)
-    let mut perms = fs::metadata(&tmp_file_path)?.permissions();
-    perms.set_mode(0o600);
-    fs::set_permissions(&tmp_file_path, perms)?;
+    match input.trim().to_lowercase().as_str() {
+        "e" => {
+            let note = Note {
+                path: note_path,
+                target,
+                heading: format!("doasedit has warnings about {}:", target.display()),
+                messages: &messages,
+            };
+            open_file_at_problem(
+                tmp_file_path,
+                warnings[0].line,
+                &note,
+                &editor_or_default(editor_cmd)?,
+            )?;
+            Ok(false)
+        }
+        "a" => Err(user_abort()),
+        _ => Ok(true),
+    }
+}
 
-    let mut perms = fs::metadata(&tmp_copy_path)?.permissions();
-    perms.set_mode(0o600);
-    fs::set_permissions(&tmp_copy_path, perms)?;
+/// Write a note to the sidecar file shown next to the target
+fn write_note(note: &Note) -> Result<()> {
+    if let Some(parent) = note.path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
 
-    Ok((tmp_file_path, tmp_copy_path))
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(note.path)?;
+    writeln!(file, "{}", note.heading)?;
+    writeln!(file)?;
+    for message in note.messages {
+        writeln!(file, "{}", message)?;
+    }
+    writeln!(file)?;
+    writeln!(
+        file,
+        "Make your changes in {}; this note is not saved.",
+        note.target.display()
+    )?;
+    Ok(())
 }
 
-/// Read the original file content into the temporary file
-pub fn copy_original_content(
-    original_path: &Path,
-    temp_file_path: &Path,
-    use_doas: bool,
-) -> Result<()> {
-    if use_doas {
-        let output = Command::new("doas")
-            .arg("cat")
-            .arg(original_path)
-            .output()
-            .map_err(|_| doas_cat_permission_denied())?;
-
-        if !output.status.success() {
-            return Err(doas_cat_permission_denied());
+/// Subdirectory of the session's temporary directory holding the copies the
+/// editor sees
+const EDIT_DIR: &str = "edit";
+
+/// Subdirectory holding the untouched snapshots, kept out of the editor's tree
+const SNAPSHOT_DIR: &str = "snapshot";
+
+/// Subdirectory holding the validation errors shown next to a copy
+const NOTE_DIR: &str = "notes";
+
+/// Create the temporary copy and comparison snapshot for a target
+///
+/// Both mirror the target's absolute path, so `/etc/nginx/nginx.conf` is
+/// edited as `edit/etc/nginx/nginx.conf` and targets with the same name never
+/// share a temporary file. Directories are created 0700 and files 0600.
+pub fn create_secure_temp_copy(
+    absolute_path: &Path,
+    temp_dir: &Path,
+) -> Result<(PathBuf, PathBuf)> {
+    let tmp_file_path = mirror_path(&temp_dir.join(EDIT_DIR), absolute_path);
+    let tmp_copy_path = mirror_path(&temp_dir.join(SNAPSHOT_DIR), absolute_path);
+
+    for path in [&tmp_file_path, &tmp_copy_path] {
+        if let Some(parent) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
         }
 
-        fs::write(temp_file_path, output.stdout)?;
-    } else {
-        fs::copy(original_path, temp_file_path)?;
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+        {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(file_given_twice(&absolute_path.display().to_string()));
+            }
+            Err(e) => return Err(e.into()),
+        }
     }
 
-    Ok(())
+    Ok((tmp_file_path, tmp_copy_path))
+}
+
+/// Where the validation error for a target is written when it is edited again
+///
+/// The note mirrors the target's path like the copies do, in a tree of its
+/// own so it can never take the place of another target's copy.
+pub fn validation_note_path(absolute_path: &Path, temp_dir: &Path) -> PathBuf {
+    let mut note = mirror_path(&temp_dir.join(NOTE_DIR), absolute_path).into_os_string();
+    note.push(".error");
+    PathBuf::from(note)
 }
 
 /// Create a copy of the temporary file for comparison
 pub fn create_comparison_copy(tmp_file_path: &Path, tmp_copy_path: &Path) -> Result<()> {
-    fs::copy(tmp_file_path, tmp_copy_path)?;
+    fs::write(tmp_copy_path, fs::read(tmp_file_path)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_line(program: &str) -> Vec<String> {
+        let editor = EditorCommand {
+            program: PathBuf::from(program),
+            args: Vec::new(),
+        };
+        editor
+            .file_at_line(Path::new("/tmp/edit/etc/hosts"), 12)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn opens_files_at_a_line() {
+        let plus = ["+12", "/tmp/edit/etc/hosts"];
+        for program in ["/usr/bin/vi", "vim", "nvim", "nano", "emacs", "emacsclient"] {
+            assert_eq!(at_line(program), plus, "{}", program);
+        }
+        assert_eq!(at_line("/usr/bin/code"), ["-g", "/tmp/edit/etc/hosts:12"]);
+        assert_eq!(at_line("codium"), ["-g", "/tmp/edit/etc/hosts:12"]);
+        assert_eq!(at_line("subl"), ["/tmp/edit/etc/hosts:12"]);
+        assert_eq!(at_line("hx"), ["/tmp/edit/etc/hosts:12"]);
+        // An editor that may not take a line only gets the file
+        assert_eq!(at_line("/opt/bin/ed"), ["/tmp/edit/etc/hosts"]);
+        assert_eq!(at_line("vim.tiny"), ["/tmp/edit/etc/hosts"]);
+    }
+
+    #[test]
+    fn knows_multi_file_editors() {
+        let editor = |program: &str| EditorCommand {
+            program: PathBuf::from(program),
+            args: Vec::new(),
+        };
+        assert!(editor("/usr/bin/vim").supports_multiple_files());
+        assert!(editor("code").supports_multiple_files());
+        assert!(!editor("/usr/bin/vi").supports_multiple_files());
+        assert!(!editor("ed").supports_multiple_files());
+        assert_eq!(
+            editor("nvim")
+                .multi_file_entry()
+                .and_then(|(_, flag)| *flag),
+            Some("-O")
+        );
+    }
+}
@@ -1,98 +1,482 @@
+use nix::sys::signal::Signal;
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Every file was handled
+pub const EXIT_OK: i32 = 0;
+/// At least one file could not be edited
+pub const EXIT_FAILURE: i32 = 1;
+/// doasedit was invoked or configured incorrectly
+pub const EXIT_USAGE: i32 = 2;
+/// The user chose to abort
+pub const EXIT_ABORTED: i32 = 3;
+/// Privileges could not be obtained
+pub const EXIT_PRIVILEGE: i32 = 4;
 
 #[derive(Debug)]
-pub struct DoaseditError(String);
+pub enum DoaseditError {
+    RootUserNotAllowed,
+    UnknownBackend {
+        backend: String,
+    },
+    /// The backend is not installed or could not be started
+    BackendUnavailable {
+        backend: String,
+        source: Option<io::Error>,
+    },
+    AuthenticationFailed {
+        backend: String,
+    },
+    /// The privileged helper misbehaved or went away
+    HelperProtocol {
+        message: String,
+    },
+    /// The helper ran a request as root and it failed
+    PrivilegedCommand {
+        command: String,
+        stderr: String,
+    },
+    Config {
+        path: PathBuf,
+        /// 0 if the error is not about a particular line
+        line: usize,
+        message: String,
+    },
+    NoEditorSpecified,
+    EditorNotFound {
+        program: String,
+    },
+    EditorSpawn {
+        editor: PathBuf,
+        source: io::Error,
+    },
+    EditorFailed,
+    UnbalancedQuotes {
+        var: String,
+        value: String,
+    },
+    InvalidPath {
+        path: PathBuf,
+    },
+    FileGivenTwice {
+        path: PathBuf,
+    },
+    IsDirectory {
+        path: PathBuf,
+    },
+    NotRegularFile {
+        path: PathBuf,
+    },
+    OwnFile {
+        path: PathBuf,
+    },
+    CreateInOwnDirectory {
+        path: PathBuf,
+    },
+    CreateInWritableDirectory {
+        path: PathBuf,
+    },
+    NoSuchDirectory {
+        path: PathBuf,
+    },
+    UserWritableFile {
+        path: PathBuf,
+    },
+    /// The path led somewhere else when the helper resolved it
+    TargetMoved {
+        path: PathBuf,
+    },
+    Validation {
+        validator: String,
+        message: String,
+    },
+    /// Writing a privilege configuration would take the user's own access away
+    Lockout {
+        path: PathBuf,
+        reason: String,
+    },
+    NothingToRecover {
+        path: PathBuf,
+    },
+    /// A question came up with no terminal to answer it on
+    NoTerminal,
+    /// A signal, or a failure to read the user's answer
+    Interrupted {
+        signal: Option<i32>,
+    },
+    Aborted,
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+}
+
+impl DoaseditError {
+    /// The status doasedit exits with when this error ends the run
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            DoaseditError::RootUserNotAllowed
+            | DoaseditError::UnknownBackend { .. }
+            | DoaseditError::Config { .. }
+            | DoaseditError::NoEditorSpecified
+            | DoaseditError::EditorNotFound { .. }
+            | DoaseditError::UnbalancedQuotes { .. } => EXIT_USAGE,
+            DoaseditError::BackendUnavailable { .. }
+            | DoaseditError::AuthenticationFailed { .. }
+            | DoaseditError::HelperProtocol { .. } => EXIT_PRIVILEGE,
+            DoaseditError::Interrupted {
+                signal: Some(signo),
+            } => 128 + signo,
+            DoaseditError::Interrupted { signal: None } | DoaseditError::Aborted => EXIT_ABORTED,
+            _ => EXIT_FAILURE,
+        }
+    }
+
+    /// A stable, machine-readable name for the kind of error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DoaseditError::RootUserNotAllowed => "root-user-not-allowed",
+            DoaseditError::UnknownBackend { .. } => "unknown-backend",
+            DoaseditError::BackendUnavailable { .. } => "backend-unavailable",
+            DoaseditError::AuthenticationFailed { .. } => "authentication-failed",
+            DoaseditError::HelperProtocol { .. } => "helper-protocol",
+            DoaseditError::PrivilegedCommand { .. } => "privileged-command",
+            DoaseditError::Config { .. } => "config",
+            DoaseditError::NoEditorSpecified => "no-editor-specified",
+            DoaseditError::EditorNotFound { .. } => "editor-not-found",
+            DoaseditError::EditorSpawn { .. } => "editor-spawn",
+            DoaseditError::EditorFailed => "editor-failed",
+            DoaseditError::UnbalancedQuotes { .. } => "unbalanced-quotes",
+            DoaseditError::InvalidPath { .. } => "invalid-path",
+            DoaseditError::FileGivenTwice { .. } => "file-given-twice",
+            DoaseditError::IsDirectory { .. } => "is-directory",
+            DoaseditError::NotRegularFile { .. } => "not-regular-file",
+            DoaseditError::OwnFile { .. } => "own-file",
+            DoaseditError::CreateInOwnDirectory { .. } => "create-in-own-directory",
+            DoaseditError::CreateInWritableDirectory { .. } => "create-in-writable-directory",
+            DoaseditError::NoSuchDirectory { .. } => "no-such-directory",
+            DoaseditError::UserWritableFile { .. } => "user-writable-file",
+            DoaseditError::TargetMoved { .. } => "target-moved",
+            DoaseditError::Validation { .. } => "validation",
+            DoaseditError::Lockout { .. } => "lockout",
+            DoaseditError::NothingToRecover { .. } => "nothing-to-recover",
+            DoaseditError::NoTerminal => "no-terminal",
+            DoaseditError::Interrupted { .. } => "interrupted",
+            DoaseditError::Aborted => "aborted",
+            DoaseditError::Io { .. } => "io",
+        }
+    }
+}
 
 impl fmt::Display for DoaseditError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            DoaseditError::RootUserNotAllowed => {
+                write!(f, "using this program as root is not permitted")
+            }
+            DoaseditError::UnknownBackend { backend } => write!(
+                f,
+                "unknown privilege backend '{}' (expected doas, sudo, run0 or su)",
+                backend
+            ),
+            DoaseditError::BackendUnavailable { backend, .. } => {
+                write!(f, "unable to run '{}'", backend)
+            }
+            DoaseditError::AuthenticationFailed { backend } => {
+                write!(f, "{}: 3 incorrect password attempts", backend)
+            }
+            DoaseditError::HelperProtocol { message } => {
+                write!(f, "privileged helper: {}", message)
+            }
+            DoaseditError::PrivilegedCommand { command, stderr } => {
+                write!(f, "privileged operation failed: {}: {}", command, stderr)
+            }
+            DoaseditError::Config {
+                path,
+                line,
+                message,
+            } => {
+                if *line == 0 {
+                    write!(f, "{}: {}", path.display(), message)
+                } else {
+                    write!(f, "{}:{}: {}", path.display(), line, message)
+                }
+            }
+            DoaseditError::NoEditorSpecified => write!(f, "no editor specified"),
+            DoaseditError::EditorNotFound { program } => {
+                write!(f, "editor '{}' not found in PATH", program)
+            }
+            DoaseditError::EditorSpawn { editor, source } => {
+                write!(f, "cannot run editor '{}': {}", editor.display(), source)
+            }
+            DoaseditError::EditorFailed => write!(f, "editor exited with non-zero status"),
+            DoaseditError::UnbalancedQuotes { var, value } => {
+                write!(f, "{}: unbalanced quotes in '{}'", var, value)
+            }
+            DoaseditError::InvalidPath { path } => {
+                write!(f, "{}: invalid file path", path.display())
+            }
+            DoaseditError::FileGivenTwice { path } => {
+                write!(f, "{}: given more than once", path.display())
+            }
+            DoaseditError::IsDirectory { path } => {
+                write!(f, "{}: cannot edit directories", path.display())
+            }
+            DoaseditError::NotRegularFile { path } => {
+                write!(f, "{}: not a regular file", path.display())
+            }
+            DoaseditError::OwnFile { path } => write!(
+                f,
+                "{}: editing your own files is not permitted",
+                path.display()
+            ),
+            DoaseditError::CreateInOwnDirectory { path } => write!(
+                f,
+                "{}: creating files in your own directory is not permitted",
+                path.display()
+            ),
+            DoaseditError::CreateInWritableDirectory { path } => write!(
+                f,
+                "{}: creating files in a user-writable directory is not permitted",
+                path.display()
+            ),
+            DoaseditError::NoSuchDirectory { path } => {
+                write!(f, "{}: no such directory", path.display())
+            }
+            DoaseditError::UserWritableFile { path } => write!(
+                f,
+                "{}: editing user-readable and -writable files is not permitted",
+                path.display()
+            ),
+            DoaseditError::Validation { validator, message } => {
+                write!(f, "{} validation error: {}", validator, message)
+            }
+            DoaseditError::TargetMoved { path } => write!(
+                f,
+                "{}: now leads to a different file than when editing started",
+                path.display()
+            ),
+            DoaseditError::Lockout { path, reason } => write!(
+                f,
+                "{}: {}; pass --i-know-what-im-doing to write it anyway",
+                path.display(),
+                reason
+            ),
+            DoaseditError::NothingToRecover { path } => {
+                write!(f, "{}: no saved edits to recover", path.display())
+            }
+            DoaseditError::NoTerminal => {
+                write!(
+                    f,
+                    "an answer is needed but standard input is not a terminal"
+                )
+            }
+            DoaseditError::Interrupted { signal: None } => write!(f, "interrupted"),
+            DoaseditError::Interrupted {
+                signal: Some(signo),
+            } => match Signal::try_from(*signo) {
+                Ok(signal) => write!(f, "interrupted by {}", signal.as_str()),
+                Err(_) => write!(f, "interrupted by signal {}", signo),
+            },
+            DoaseditError::Aborted => write!(f, "aborted by user"),
+            DoaseditError::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            DoaseditError::Io { path: None, source } => write!(f, "{}", source),
+        }
     }
 }
 
-impl std::error::Error for DoaseditError {}
+impl std::error::Error for DoaseditError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DoaseditError::BackendUnavailable {
+                source: Some(source),
+                ..
+            }
+            | DoaseditError::EditorSpawn { source, .. }
+            | DoaseditError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 pub type Result<T> = std::result::Result<T, DoaseditError>;
 
 // Helper functions to create error messages
 pub fn root_user_not_allowed() -> DoaseditError {
-    DoaseditError("using this program as root is not permitted".to_string())
+    DoaseditError::RootUserNotAllowed
 }
 
-pub fn doas_unavailable() -> DoaseditError {
-    DoaseditError("unable to run 'doas'".to_string())
+pub fn backend_unavailable(backend: &str) -> DoaseditError {
+    DoaseditError::BackendUnavailable {
+        backend: backend.to_string(),
+        source: None,
+    }
+}
+
+pub fn backend_spawn_failed(backend: &str, source: io::Error) -> DoaseditError {
+    DoaseditError::BackendUnavailable {
+        backend: backend.to_string(),
+        source: Some(source),
+    }
+}
+
+pub fn unknown_backend(backend: &str) -> DoaseditError {
+    DoaseditError::UnknownBackend {
+        backend: backend.to_string(),
+    }
+}
+
+pub fn helper_protocol_error(msg: &str) -> DoaseditError {
+    DoaseditError::HelperProtocol {
+        message: msg.to_string(),
+    }
+}
+
+pub fn helper_request_failed(command: &str, stderr: &str) -> DoaseditError {
+    DoaseditError::PrivilegedCommand {
+        command: command.to_string(),
+        stderr: stderr.to_string(),
+    }
+}
+
+pub fn config_error(path: &str, line: usize, msg: &str) -> DoaseditError {
+    DoaseditError::Config {
+        path: PathBuf::from(path),
+        line,
+        message: msg.to_string(),
+    }
 }
 
 pub fn no_editor_specified() -> DoaseditError {
-    DoaseditError("no editor specified".to_string())
+    DoaseditError::NoEditorSpecified
+}
+
+pub fn editor_spawn_failed(editor: &Path, source: io::Error) -> DoaseditError {
+    DoaseditError::EditorSpawn {
+        editor: editor.to_path_buf(),
+        source,
+    }
+}
+
+pub fn editor_not_found(program: &str) -> DoaseditError {
+    DoaseditError::EditorNotFound {
+        program: program.to_string(),
+    }
+}
+
+pub fn unbalanced_quotes(var: &str, value: &str) -> DoaseditError {
+    DoaseditError::UnbalancedQuotes {
+        var: var.to_string(),
+        value: value.to_string(),
+    }
 }
 
-pub fn invalid_editor(editor: &str) -> DoaseditError {
-    DoaseditError(format!("invalid editor command: '{}'", editor))
+pub fn invalid_path(path: &Path) -> DoaseditError {
+    DoaseditError::InvalidPath {
+        path: path.to_path_buf(),
+    }
+}
+
+pub fn file_given_twice(path: &str) -> DoaseditError {
+    DoaseditError::FileGivenTwice { path: path.into() }
 }
 
 pub fn cannot_edit_directory(path: &str) -> DoaseditError {
-    DoaseditError(format!("{}: cannot edit directories", path))
+    DoaseditError::IsDirectory { path: path.into() }
 }
 
 pub fn not_regular_file(path: &str) -> DoaseditError {
-    DoaseditError(format!("{}: not a regular file", path))
+    DoaseditError::NotRegularFile { path: path.into() }
 }
 
 pub fn cannot_edit_own_file(path: &str) -> DoaseditError {
-    DoaseditError(format!("{}: editing your own files is not permitted", path))
+    DoaseditError::OwnFile { path: path.into() }
 }
 
 pub fn cannot_create_file_in_own_dir(path: &str) -> DoaseditError {
-    DoaseditError(format!(
-        "{}: creating files in your own directory is not permitted",
-        path
-    ))
+    DoaseditError::CreateInOwnDirectory { path: path.into() }
 }
 
 pub fn cannot_create_file_in_writable_dir(path: &str) -> DoaseditError {
-    DoaseditError(format!(
-        "{}: creating files in a user-writable directory is not permitted",
-        path
-    ))
+    DoaseditError::CreateInWritableDirectory { path: path.into() }
 }
 
 pub fn no_directory_exists(path: &str) -> DoaseditError {
-    DoaseditError(format!("{}: no such directory", path))
+    DoaseditError::NoSuchDirectory { path: path.into() }
 }
 
 pub fn cannot_edit_readable_writable_file(path: &str) -> DoaseditError {
-    DoaseditError(format!(
-        "{}: editing user-readable and -writable files is not permitted",
-        path
-    ))
+    DoaseditError::UserWritableFile { path: path.into() }
 }
 
-pub fn doas_cat_permission_denied() -> DoaseditError {
-    DoaseditError("you are not permitted to call 'doas cat'".to_string())
+pub fn target_moved(path: &Path) -> DoaseditError {
+    DoaseditError::TargetMoved {
+        path: path.to_path_buf(),
+    }
 }
 
-pub fn three_incorrect_password_attempts() -> DoaseditError {
-    DoaseditError("3 incorrect password attempts".to_string())
+pub fn three_incorrect_password_attempts(backend: &str) -> DoaseditError {
+    DoaseditError::AuthenticationFailed {
+        backend: backend.to_string(),
+    }
 }
 
-pub fn doas_validation_error(msg: &str) -> DoaseditError {
-    DoaseditError(format!("doas validation error: {}", msg))
+pub fn validation_error(validator: &str, msg: &str) -> DoaseditError {
+    DoaseditError::Validation {
+        validator: validator.to_string(),
+        message: msg.to_string(),
+    }
+}
+
+pub fn lockout(path: &Path, reason: &str) -> DoaseditError {
+    DoaseditError::Lockout {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+pub fn nothing_to_recover(path: &Path) -> DoaseditError {
+    DoaseditError::NothingToRecover {
+        path: path.to_path_buf(),
+    }
+}
+
+pub fn no_terminal() -> DoaseditError {
+    DoaseditError::NoTerminal
 }
 
 pub fn interrupted() -> DoaseditError {
-    DoaseditError("interrupted".to_string())
+    DoaseditError::Interrupted { signal: None }
+}
+
+pub fn interrupted_by(signo: i32) -> DoaseditError {
+    DoaseditError::Interrupted {
+        signal: Some(signo),
+    }
 }
 
 pub fn editor_error() -> DoaseditError {
-    DoaseditError("editor exited with non-zero status".to_string())
+    DoaseditError::EditorFailed
 }
 
 pub fn user_abort() -> DoaseditError {
-    DoaseditError("aborted by user".to_string())
+    DoaseditError::Aborted
+}
+
+/// An I/O error on a known file
+pub fn io_error(path: &Path, source: io::Error) -> DoaseditError {
+    DoaseditError::Io {
+        path: Some(path.to_path_buf()),
+        source,
+    }
 }
 
-impl From<std::io::Error> for DoaseditError {
-    fn from(err: std::io::Error) -> Self {
-        DoaseditError(err.to_string())
+impl From<io::Error> for DoaseditError {
+    fn from(source: io::Error) -> Self {
+        DoaseditError::Io { path: None, source }
     }
 }
@@ -0,0 +1,227 @@
+use crate::access::{Credentials, FilePermissions};
+use crate::backup::BackupPolicy;
+use crate::error::{cannot_edit_directory, DoaseditError};
+use crate::file_handler::{edit_rules, resolve_as_user, target_status, writes_as_user};
+use crate::resolve::{fd_path, SymlinkPolicy, Target};
+use crate::utils::{get_parent_directory, is_directory_path};
+use crate::validate::Registry;
+use nix::unistd::{Gid, Group, Uid, User};
+use std::path::Path;
+
+/// Print why `file_path` may or may not be edited, returning whether it may
+///
+/// The target is resolved, inspected and judged by the same functions
+/// `doasedit` uses before opening the editor, and every rule is shown along
+/// with the facts it rests on. Nothing is opened for editing and the
+/// privileged helper is not started, so this never asks for a password;
+/// what the user cannot see is reported as such. `backend` is the privilege
+/// backend that would be used, or why there is none.
+pub fn explain(
+    file_path: &str,
+    backend: Result<&str, DoaseditError>,
+    symlinks: SymlinkPolicy,
+    backup: &BackupPolicy,
+    validators: &Registry,
+) -> bool {
+    let mut report = Report::default();
+    let root = match &backend {
+        Ok(name) => format!("as root through {}", name),
+        Err(_) => "as root, but no privilege backend is available".to_string(),
+    };
+
+    println!("doasedit: {}", file_path);
+
+    if is_directory_path(file_path) {
+        report.check(Err(cannot_edit_directory(file_path)));
+        return report.verdict();
+    }
+
+    let absolute_path = match std::path::absolute(file_path) {
+        Ok(absolute_path) => absolute_path,
+        Err(e) => {
+            report.check(Err(e.into()));
+            return report.verdict();
+        }
+    };
+
+    if let Ok(me) = Credentials::current() {
+        let groups: Vec<String> = me.groups.iter().map(|&gid| group_name(gid)).collect();
+        report.fact(
+            "you",
+            format!("{}, groups {}", user_name(me.uid), groups.join(", ")),
+        );
+    }
+
+    report.fact("symlinks", symlinks.name().to_string());
+    match resolve_as_user(&absolute_path, symlinks) {
+        Ok(Some(target)) => {
+            if target.path() != absolute_path {
+                report.fact("resolves to", target.path().display().to_string());
+            }
+            explain_target(&mut report, file_path, &target, backup, &root);
+        }
+        Ok(None) => {
+            report.fact(
+                "file",
+                "not visible to you; root would look it up".to_string(),
+            );
+            report.access("read", false, &root);
+            report.access("write", false, &root);
+        }
+        Err(e) => report.check(Err(e)),
+    }
+
+    if let Err(e) = backend {
+        if report.root_needed {
+            report.check(Err(e));
+        }
+    }
+
+    report.fact(
+        "validator",
+        validators
+            .find(&absolute_path)
+            .map_or("none".to_string(), |validator| validator.name().to_string()),
+    );
+
+    report.verdict()
+}
+
+/// The file and its directory as the user resolved them, the rules they
+/// pass or fail, and how the file would be read and written
+fn explain_target(
+    report: &mut Report,
+    file_path: &str,
+    target: &Target,
+    backup: &BackupPolicy,
+    root: &str,
+) {
+    let info = match target_status(target) {
+        Ok(info) => info,
+        Err(e) => return report.check(Err(e)),
+    };
+
+    if !info.exists {
+        report.fact("file", "does not exist and would be created".to_string());
+    } else if info.is_directory {
+        report.fact("file", "a directory".to_string());
+    } else {
+        report.fact("file", describe_permissions(target.path()));
+        report.fact("your access", describe_access(info.readable, info.writable));
+    }
+    report.fact(
+        "directory",
+        format!(
+            "{}: {}",
+            get_parent_directory(target.path()).display(),
+            describe_permissions(&fd_path(target.dir()))
+        ),
+    );
+    report.fact(
+        "dir access",
+        if info.dir_writable {
+            "you may create and remove files in it".to_string()
+        } else {
+            "you may not create or remove files in it".to_string()
+        },
+    );
+
+    let mut allowed = true;
+    for rule in edit_rules(file_path, &info) {
+        allowed &= rule.is_ok();
+        report.check(rule);
+    }
+    if !allowed {
+        return;
+    }
+
+    if info.exists {
+        report.access("read", info.readable, root);
+    }
+    match writes_as_user(target, info.writable, backup) {
+        Ok(as_user) => report.access("write", as_user, root),
+        Err(e) => report.check(Err(e)),
+    }
+}
+
+/// Facts and rule outcomes, printed as they are added
+#[derive(Default)]
+struct Report {
+    refused: bool,
+    root_needed: bool,
+}
+
+impl Report {
+    fn fact(&mut self, label: &str, value: String) {
+        println!("  {:<12} {}", label, value);
+    }
+
+    /// How reading or writing would be done: as the user, or as `root`
+    fn access(&mut self, label: &str, as_user: bool, root: &str) {
+        if as_user {
+            self.fact(label, "as you".to_string());
+        } else {
+            self.root_needed = true;
+            self.fact(label, root.to_string());
+        }
+    }
+
+    fn check(&mut self, result: Result<&str, DoaseditError>) {
+        match result {
+            Ok(rule) => println!("  {:<12} {}", "ok", rule),
+            Err(e) => {
+                self.refused = true;
+                println!("  {:<12} {}", "refused", e);
+            }
+        }
+    }
+
+    fn verdict(&self) -> bool {
+        if self.refused {
+            println!("  {:<12} doasedit will not edit this file", "verdict");
+        } else {
+            println!("  {:<12} doasedit will edit this file", "verdict");
+        }
+        !self.refused
+    }
+}
+
+/// Owner, group, mode and any ACL entries, e.g. `owner root (0), group
+/// wheel (10), mode 0640`
+fn describe_permissions(path: &Path) -> String {
+    let permissions = match FilePermissions::read(path) {
+        Ok(permissions) => permissions,
+        Err(e) => return format!("cannot read permissions: {}", e),
+    };
+
+    let mut description = format!(
+        "owner {}, group {}, mode {:04o}",
+        user_name(permissions.uid),
+        group_name(permissions.gid),
+        permissions.mode
+    );
+    if !permissions.acl.is_empty() {
+        let entries: Vec<String> = permissions.acl.iter().map(|e| e.to_string()).collect();
+        description.push_str(&format!(", ACL {}", entries.join(",")));
+    }
+    description
+}
+
+fn describe_access(readable: bool, writable: bool) -> String {
+    let yes_no = |allowed: bool| if allowed { "yes" } else { "no" };
+    format!("read {}, write {}", yes_no(readable), yes_no(writable))
+}
+
+fn user_name(uid: u32) -> String {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => format!("{} ({})", user.name, uid),
+        _ => uid.to_string(),
+    }
+}
+
+fn group_name(gid: u32) -> String {
+    match Group::from_gid(Gid::from_raw(gid)) {
+        Ok(Some(group)) => format!("{} ({})", group.name, gid),
+        _ => gid.to_string(),
+    }
+}
@@ -1,230 +1,760 @@
+use crate::access::{user_can, Credentials, READ, SEARCH, WRITE};
+use crate::atomic::{write_with_backup, WriteOutcome};
+use crate::backup::{user_can_back_up, BackupPolicy};
+use crate::conflict::{check_concurrent_change, ConcurrentChange};
+use crate::editor::{
+    create_comparison_copy, create_secure_temp_copy, editor_or_default, open_file_with_editor,
+    open_files_with_editor, run_validator, validation_note_path, EditorCommand,
+};
 use crate::error::{
     cannot_create_file_in_own_dir, cannot_create_file_in_writable_dir, cannot_edit_directory,
-    cannot_edit_own_file, cannot_edit_readable_writable_file, doas_unavailable,
-    no_directory_exists, not_regular_file, three_incorrect_password_attempts, Result,
+    cannot_edit_own_file, cannot_edit_readable_writable_file, io_error, lockout,
+    no_directory_exists, not_regular_file, target_moved, Result, EXIT_ABORTED, EXIT_FAILURE,
+    EXIT_OK, EXIT_PRIVILEGE,
 };
+use crate::helper::{Handle, Helper};
+use crate::lockout::check_lockout;
+use crate::recovery;
+use crate::resolve::{fd_path, resolve, SymlinkPolicy, Target};
+use crate::review::{review_changes, ReviewChoice};
+use crate::signals::{pending_signal, CriticalSection};
 use crate::utils::{
-    files_match, get_file_metadata_with_doas, get_filename, get_parent_directory,
-    is_dir_owned_by_user, is_dir_writable_by_user, is_directory_path, is_doas_config_file,
-    is_file_owned_by_user, is_file_writable_by_user,
+    can_prompt, files_match, get_filename, get_parent_directory, is_directory_path, read_user_input,
 };
+use crate::validate::Registry;
+use nix::sys::stat::{fstat, SFlag};
 use nix::unistd::getuid;
 use std::fs;
+use std::io::{self, Read};
 
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 
 /// Information about a file's status
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct FileInfo {
     pub exists: bool,
     pub readable: bool,
     pub writable: bool,
     pub is_directory: bool,
     pub is_owned_by_user: bool,
+    /// The directory the file is in, or would be created in, belongs to the
+    /// user
+    pub dir_owned_by_user: bool,
+    /// The user may create and remove files in that directory
+    pub dir_writable: bool,
+}
+
+/// Settings that apply to every file edited in a session
+#[derive(Default)]
+pub struct EditOptions {
+    pub backup: BackupPolicy,
+    /// Show a diff and ask for confirmation before writing
+    pub review: bool,
+    pub validators: Registry,
+    /// Carry on with the remaining files after one fails
+    pub keep_going: bool,
+    pub symlinks: SymlinkPolicy,
+    /// Write privilege configurations even if they lock the user out
+    pub allow_lockout: bool,
+}
+
+/// What happened to a file that was processed without error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOutcome {
+    Written {
+        /// The user chose to write despite a validation error
+        validation_overridden: bool,
+    },
+    Unchanged,
+    Discarded,
+    /// Someone else changed the file and the user kept their version
+    KeptCurrent,
+}
+
+/// The result for one file named on the command line, `None` if skipped
+pub struct FileReport {
+    pub file_path: String,
+    pub result: Option<Result<FileOutcome>>,
 }
 
-/// Check the status of a file and its permissions
-pub fn check_file_status(path: &Path) -> Result<FileInfo> {
+/// Check the status of a pinned target and the user's access to it
+///
+/// Everything is judged on the file the target was resolved to, never on the
+/// path again: through the user's own resolution if they could make one, and
+/// otherwise through the helper's.
+pub fn check_file_status(target: &mut PinnedTarget, helper: &mut Helper) -> Result<FileInfo> {
+    if let Some(local) = &target.local {
+        return target_status(local);
+    }
+
+    // The user cannot even look up the path, so whatever the file's own
+    // permissions say, reading and writing it takes the helper
     let uid = getuid().as_raw();
+    let handle = target.remote(helper)?;
+    let stat = helper.stat(handle)?;
+    Ok(FileInfo {
+        exists: stat.is_some(),
+        is_directory: stat.as_ref().is_some_and(|stat| !stat.is_file),
+        is_owned_by_user: stat.is_some_and(|stat| stat.is_file && stat.uid == uid),
+        ..FileInfo::default()
+    })
+}
 
-    // First try without doas
-    if path.exists() {
-        let metadata = fs::metadata(path)?;
-        let is_directory = metadata.is_dir();
-
-        if is_directory {
-            return Ok(FileInfo {
-                exists: true,
-                readable: false,
-                writable: false,
-                is_directory: true,
-                is_owned_by_user: false,
-            });
-        }
+/// Resolve a target as the user, or `None` if they may not look it up
+pub fn resolve_as_user(path: &Path, symlinks: SymlinkPolicy) -> Result<Option<Target>> {
+    match resolve(path, symlinks) {
+        Ok(target) => Ok(Some(target)),
+        // Root will have to look instead
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(no_directory_exists(
+            &get_parent_directory(path).display().to_string(),
+        )),
+        Err(e) => Err(io_error(path, e)),
+    }
+}
+
+/// The status of a target the user resolved, and their access to it and to
+/// its directory
+pub fn target_status(target: &Target) -> Result<FileInfo> {
+    let uid = getuid().as_raw();
+    let path = target.path();
+    let dir = target.dir();
+    let mut info = FileInfo {
+        dir_owned_by_user: fstat(dir).map_err(|e| io_error(path, e.into()))?.st_uid == uid,
+        dir_writable: user_can(&fd_path(dir), WRITE | SEARCH).map_err(|e| io_error(path, e))?,
+        ..FileInfo::default()
+    };
 
-        let is_owned = is_file_owned_by_user(path)?;
-        let readable = metadata.permissions().readonly() == false;
-        let writable = is_file_writable_by_user(path)?;
+    let Some(stat) = target.stat().map_err(|e| io_error(path, e))? else {
+        return Ok(info);
+    };
+    info.exists = true;
+    if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR {
+        info.is_directory = true;
+        return Ok(info);
+    }
+    info.readable = target.user_can(READ).map_err(|e| io_error(path, e))?;
+    info.writable = target.user_can(WRITE).map_err(|e| io_error(path, e))?;
+    info.is_owned_by_user = stat.st_uid == uid;
+    Ok(info)
+}
 
-        return Ok(FileInfo {
-            exists: true,
-            readable,
-            writable,
-            is_directory: false,
-            is_owned_by_user: is_owned,
-        });
+/// The rules a file must pass to be edited, in order, each either what it
+/// found or why it refuses
+///
+/// Editing is for files only root may change; a file the user could change
+/// themselves, or create themselves, is theirs to edit without doasedit.
+pub fn edit_rules(file_path: &str, info: &FileInfo) -> Vec<Result<&'static str>> {
+    if info.is_directory {
+        return vec![Err(not_regular_file(file_path))];
     }
 
-    // Try with doas
-    let check_existence = Command::new("doas")
-        .arg("test")
-        .arg("-e")
-        .arg(path)
-        .output()
-        .map_err(|_| doas_unavailable())?;
-
-    if check_existence.status.success() {
-        let check_is_file = Command::new("doas")
-            .arg("test")
-            .arg("-f")
-            .arg(path)
-            .output()
-            .map_err(|_| doas_unavailable())?;
-
-        if !check_is_file.status.success() {
-            return Ok(FileInfo {
-                exists: true,
-                readable: false,
-                writable: false,
-                is_directory: true,
-                is_owned_by_user: false,
-            });
-        }
+    if info.exists {
+        vec![
+            if info.is_owned_by_user {
+                Err(cannot_edit_own_file(file_path))
+            } else {
+                Ok("not owned by you")
+            },
+            if info.readable && info.writable {
+                Err(cannot_edit_readable_writable_file(file_path))
+            } else {
+                Ok("not both readable and writable by you")
+            },
+        ]
+    } else {
+        vec![
+            if info.dir_owned_by_user {
+                Err(cannot_create_file_in_own_dir(file_path))
+            } else {
+                Ok("directory not owned by you")
+            },
+            if info.dir_writable {
+                Err(cannot_create_file_in_writable_dir(file_path))
+            } else {
+                Ok("directory not writable by you")
+            },
+        ]
+    }
+}
 
-        // Get metadata with doas
-        let (file_uid, mode) = get_file_metadata_with_doas(path)?;
-        let writable = mode & 0o200 != 0; // User write bit
-        let is_owned = file_uid == uid;
-
-        return Ok(FileInfo {
-            exists: true,
-            readable: true, // Assuming readable if doas can read it
-            writable,
-            is_directory: false,
-            is_owned_by_user: is_owned,
-        });
+/// Whether the user writes a target back themselves rather than root
+///
+/// Either way the file is replaced through a temporary sibling, so the user
+/// must be able to create files in its directory and give the replacement
+/// the file's owner and group, which only works for a file of their own in
+/// one of their groups. A backup the user could not read the file for or
+/// put in place takes root too, and replacing a link is always left to root.
+pub fn writes_as_user(target: &Target, writable: bool, backup: &BackupPolicy) -> Result<bool> {
+    if !writable || target.replaces_link() {
+        return Ok(false);
+    }
+    let path = target.path();
+    let Some(metadata) = target.metadata().map_err(|e| io_error(path, e))? else {
+        return Ok(false);
+    };
+    let me = Credentials::current().map_err(|e| io_error(path, e))?;
+    if metadata.uid != me.uid || !me.in_group(metadata.gid) {
+        return Ok(false);
+    }
+    if !user_can(&fd_path(target.dir()), WRITE | SEARCH).map_err(|e| io_error(path, e))? {
+        return Ok(false);
+    }
+    user_can_back_up(target, backup).map_err(|e| io_error(path, e))
+}
+
+/// A target whose content has been copied out for editing
+pub struct PreparedFile {
+    /// The path as given on the command line
+    pub file_path: String,
+    pub absolute_path: PathBuf,
+    pub file_info: FileInfo,
+    /// The copy handed to the editor
+    pub tmp_file_path: PathBuf,
+    /// The original content, to detect changes
+    pub tmp_copy_path: PathBuf,
+    /// Where a validation error is shown when the copy is edited again
+    pub note_path: PathBuf,
+    pub target: PinnedTarget,
+}
+
+/// A target resolved once, when editing started
+///
+/// The user's own resolution serves whatever the user may do themselves. The
+/// helper resolves the target only once root is needed, and has to arrive at
+/// the same directory entry, so a path changed in the meantime is caught
+/// instead of written through.
+pub struct PinnedTarget {
+    path: PathBuf,
+    symlinks: SymlinkPolicy,
+    /// `None` if the user may not look up the path
+    local: Option<Target>,
+    remote: Option<Handle>,
+}
+
+impl PinnedTarget {
+    fn resolve(path: &Path, symlinks: SymlinkPolicy) -> Result<PinnedTarget> {
+        Ok(PinnedTarget {
+            path: path.to_path_buf(),
+            symlinks,
+            local: resolve_as_user(path, symlinks)?,
+            remote: None,
+        })
     }
 
-    // File doesn't exist, check directory
-    let dir_path = get_parent_directory(path);
+    fn local(&self) -> Result<&Target> {
+        self.local
+            .as_ref()
+            .ok_or_else(|| io_error(&self.path, io::ErrorKind::PermissionDenied.into()))
+    }
 
-    if dir_path.exists() {
-        // Check if directory is owned by user
-        if is_dir_owned_by_user(&dir_path)? {
-            return Err(cannot_create_file_in_own_dir(&path.display().to_string()).into());
+    /// The helper's handle, opened the first time it is needed
+    fn remote(&mut self, helper: &mut Helper) -> Result<&Handle> {
+        if self.remote.is_none() {
+            let handle = helper.open(&self.path, self.symlinks)?.ok_or_else(|| {
+                no_directory_exists(&get_parent_directory(&self.path).display().to_string())
+            })?;
+            if let Some(local) = &self.local {
+                let identity = local.identity().map_err(|e| io_error(&self.path, e))?;
+                if identity != handle.identity {
+                    return Err(target_moved(&self.path));
+                }
+            }
+            self.remote = Some(handle);
         }
+        Ok(self.remote.as_ref().expect("handle was just opened"))
+    }
 
-        // Check if directory is writable by user
-        if is_dir_writable_by_user(&dir_path)? {
-            return Err(cannot_create_file_in_writable_dir(&path.display().to_string()).into());
+    /// The current content, read by the user if `readable` and by root
+    /// otherwise; empty if the file does not exist
+    fn read(&mut self, readable: bool, helper: &mut Helper) -> Result<Vec<u8>> {
+        let content = if readable {
+            let path = &self.path;
+            self.local()?.read().map_err(|e| io_error(path, e))?
+        } else {
+            let handle = self.remote(helper)?;
+            helper.read(handle)?
+        };
+        Ok(content.unwrap_or_default())
+    }
+
+    /// Back up and replace the content atomically, as the user if
+    /// `writable` and [`writes_as_user`] agrees, and as root otherwise
+    fn write(
+        &mut self,
+        writable: bool,
+        content: Vec<u8>,
+        helper: &mut Helper,
+        backup: &BackupPolicy,
+    ) -> Result<WriteOutcome> {
+        let in_place = match &self.local {
+            Some(local) => writes_as_user(local, writable, backup)?,
+            None => false,
+        };
+        if in_place {
+            let path = &self.path;
+            write_with_backup(self.local()?, &content, backup).map_err(|e| io_error(path, e))
+        } else {
+            let handle = self.remote(helper)?;
+            helper.write(handle, content, backup)
         }
+    }
+}
+
+/// Process every file, opening them in one editor session if `together` is set
+///
+/// Without `keep_going` processing stops at the first failure. Errors are
+/// reported as they happen; failing to obtain privileges or a signal always
+/// stops the run and the remaining files are reported as skipped.
+pub fn process_files(
+    file_paths: &[&str],
+    editor: &EditorCommand,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+    together: bool,
+) -> Vec<FileReport> {
+    let mut reports: Vec<FileReport> = file_paths
+        .iter()
+        .map(|file_path| FileReport {
+            file_path: file_path.to_string(),
+            result: None,
+        })
+        .collect();
+
+    if together && file_paths.len() > 1 {
+        process_together(&mut reports, editor, tmp_dir, helper, options);
     } else {
-        // Try with doas
-        let check_dir_existence = Command::new("doas")
-            .arg("test")
-            .arg("-d")
-            .arg(&dir_path)
-            .output()
-            .map_err(|_| doas_unavailable())?;
-
-        if !check_dir_existence.status.success() {
-            return Err(no_directory_exists(&dir_path.display().to_string()).into());
+        for report in &mut reports {
+            let result = process_file(&report.file_path, editor, tmp_dir, helper, options);
+            if !record(report, result, options) {
+                break;
+            }
         }
     }
 
-    Ok(FileInfo {
-        exists: false,
-        readable: false,
-        writable: false,
-        is_directory: false,
-        is_owned_by_user: false,
-    })
+    reports
 }
 
 /// Process a file: create temp files, open editor, validate changes, and write back
-pub fn process_file(file_path: &str, editor: &str, tmp_dir: &Path) -> Result<()> {
-    use crate::editor::{
-        copy_original_content, create_comparison_copy, create_secure_temp_copy,
-        open_file_with_editor, validate_doas_config,
+pub fn process_file(
+    file_path: &str,
+    editor: &EditorCommand,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> Result<FileOutcome> {
+    let mut prepared = prepare_file(file_path, tmp_dir, helper, options.symlinks)?;
+    let result = open_file_with_editor(&prepared.tmp_file_path, editor)
+        .and_then(|()| finish_file(&mut prepared, Some(editor), helper, options));
+    if let Err(e) = &result {
+        save_edit(&prepared, &e.to_string());
+    }
+    result
+}
+
+/// Open all files in one editor session
+///
+/// Every file is checked and copied out before the editor starts; validation,
+/// review and write-back then happen one file at a time.
+fn process_together(
+    reports: &mut [FileReport],
+    editor: &EditorCommand,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+) {
+    let mut prepared = Vec::with_capacity(reports.len());
+    for (index, report) in reports.iter_mut().enumerate() {
+        match prepare_file(&report.file_path, tmp_dir, helper, options.symlinks) {
+            Ok(file) => prepared.push((index, file)),
+            Err(e) => {
+                if !record(report, Err(e), options) {
+                    return;
+                }
+            }
+        }
+    }
+
+    if prepared.is_empty() {
+        return;
+    }
+
+    let tmp_paths: Vec<&Path> = prepared
+        .iter()
+        .map(|(_, file)| file.tmp_file_path.as_path())
+        .collect();
+    if let Err(e) = open_files_with_editor(&tmp_paths, editor) {
+        // Every file was waiting on the editor; report the failure against the first
+        for (_, file) in &prepared {
+            save_edit(file, &e.to_string());
+        }
+        let (index, _) = prepared[0];
+        record(&mut reports[index], Err(e), options);
+        return;
+    }
+
+    for position in 0..prepared.len() {
+        let (index, file) = &mut prepared[position];
+        let result = finish_file(file, Some(editor), helper, options);
+        if let Err(e) = &result {
+            save_edit(file, &e.to_string());
+        }
+        if !record(&mut reports[*index], result, options) {
+            // The files after this one were edited too but will not be written
+            let reason = match pending_signal() {
+                Some(_) => "not written because doasedit was interrupted",
+                None => "not written because an earlier file failed",
+            };
+            for (_, file) in &prepared[position + 1..] {
+                save_edit(file, reason);
+            }
+            return;
+        }
+    }
+}
+
+/// Keep an edit that was not written in the recovery store
+fn save_edit(prepared: &PreparedFile, reason: &str) {
+    // Nothing worth keeping if the file was not changed
+    if files_match(&prepared.tmp_file_path, &prepared.tmp_copy_path).unwrap_or(false) {
+        return;
+    }
+
+    match recovery::save(
+        &prepared.absolute_path,
+        &prepared.tmp_copy_path,
+        &prepared.tmp_file_path,
+        reason,
+    ) {
+        Ok(_) => eprintln!(
+            "doasedit: {}: your changes were saved; restore them with 'doasedit --recover {}'",
+            prepared.file_path,
+            prepared.absolute_path.display()
+        ),
+        Err(e) => eprintln!(
+            "doasedit: {}: could not save your changes for recovery: {}",
+            prepared.file_path, e
+        ),
+    }
+}
+
+/// Apply a saved edit through the usual validation, review and write-back
+///
+/// The snapshot is the target as it was when the edit was made, so changes
+/// made to the target since are detected and merged. The saved edit is
+/// removed once it has been dealt with.
+pub fn recover_file(
+    entry: &recovery::Entry,
+    editor: &EditorCommand,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> Result<FileOutcome> {
+    let file_path = entry.target.to_string_lossy().into_owned();
+    let mut prepared = prepare_file(&file_path, tmp_dir, helper, options.symlinks)?;
+
+    let original = fs::read(entry.original_path()).map_err(|e| io_error(&entry.dir, e))?;
+    let edited = fs::read(entry.edited_path()).map_err(|e| io_error(&entry.dir, e))?;
+    fs::write(&prepared.tmp_copy_path, original)?;
+    fs::write(&prepared.tmp_file_path, edited)?;
+
+    let outcome = finish_file(&mut prepared, Some(editor), helper, options)?;
+    entry.remove().map_err(|e| io_error(&entry.dir, e))?;
+    Ok(outcome)
+}
+
+/// Where `--stdin` and `--from` take the new content from
+pub enum ContentSource {
+    Stdin,
+    /// A file read with the user's own permissions
+    File(PathBuf),
+}
+
+impl ContentSource {
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            ContentSource::Stdin => {
+                let mut content = Vec::new();
+                io::stdin().read_to_end(&mut content)?;
+                Ok(content)
+            }
+            ContentSource::File(path) => fs::read(path).map_err(|e| io_error(path, e)),
+        }
+    }
+}
+
+/// Replace a file's content with `source` instead of opening the editor
+///
+/// The file goes through the same checks, validation, review and write-back
+/// as an edit. Questions are only asked on a terminal; without one, anything
+/// that needs an answer fails the file. No editor is needed unless the user
+/// asks to edit the content again. Content that is not written is not
+/// kept for `--recover`, since the caller still has it.
+pub fn write_from(
+    file_path: &str,
+    source: &ContentSource,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> Result<FileOutcome> {
+    let content = source.read()?;
+    let mut prepared = prepare_file(file_path, tmp_dir, helper, options.symlinks)?;
+    fs::write(&prepared.tmp_file_path, content)?;
+    finish_file(&mut prepared, None, helper, options)
+}
+
+/// Store a file's result, printing any error; returns whether to carry on
+fn record(report: &mut FileReport, result: Result<FileOutcome>, options: &EditOptions) -> bool {
+    let carry_on = match &result {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("doasedit: {}", e);
+            options.keep_going && e.exit_code() != EXIT_PRIVILEGE
+        }
     };
+    report.result = Some(result);
+    // A signal ends the run once the current file is dealt with
+    carry_on && pending_signal().is_none()
+}
+
+/// Print one line per file saying what happened to it
+pub fn print_summary(reports: &[FileReport]) {
+    let width = reports
+        .iter()
+        .map(|report| report.file_path.len())
+        .max()
+        .unwrap_or(0);
+
+    println!("doasedit: summary");
+    for report in reports {
+        let status = match &report.result {
+            None => "skipped".to_string(),
+            Some(Ok(FileOutcome::Written {
+                validation_overridden: false,
+            })) => "written".to_string(),
+            Some(Ok(FileOutcome::Written {
+                validation_overridden: true,
+            })) => "written (validation overridden)".to_string(),
+            Some(Ok(FileOutcome::Unchanged)) => "unchanged".to_string(),
+            Some(Ok(FileOutcome::Discarded)) => "discarded".to_string(),
+            Some(Ok(FileOutcome::KeptCurrent)) => "kept current version".to_string(),
+            Some(Err(e)) if e.exit_code() == EXIT_ABORTED => "aborted".to_string(),
+            Some(Err(e)) => format!("failed [{}]: {}", e.kind(), e),
+        };
+        println!("  {:<width$}  {}", report.file_path, status, width = width);
+    }
+}
 
+/// The exit status for a run, as documented in doasedit(1)
+///
+/// A signal outranks everything, then a privilege failure outranks any other
+/// failure, which outranks an abort.
+pub fn exit_code(reports: &[FileReport]) -> i32 {
+    if let Some(signo) = pending_signal() {
+        return 128 + signo;
+    }
+
+    let codes: Vec<i32> = reports
+        .iter()
+        .filter_map(|report| match &report.result {
+            Some(Err(e)) => Some(e.exit_code()),
+            _ => None,
+        })
+        .collect();
+
+    if codes.contains(&EXIT_PRIVILEGE) {
+        EXIT_PRIVILEGE
+    } else if codes.iter().any(|&code| code != EXIT_ABORTED) {
+        EXIT_FAILURE
+    } else if codes.contains(&EXIT_ABORTED) {
+        EXIT_ABORTED
+    } else {
+        EXIT_OK
+    }
+}
+
+/// Check a target and copy its content to a private temporary file
+///
+/// The target is resolved here, once; reads and the write-back go to what it
+/// resolved to.
+pub fn prepare_file(
+    file_path: &str,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    symlinks: SymlinkPolicy,
+) -> Result<PreparedFile> {
     // Check if path is a directory (ends with /)
     if is_directory_path(file_path) {
-        return Err(cannot_edit_directory(file_path).into());
+        return Err(cannot_edit_directory(file_path));
     }
 
     let path = Path::new(file_path);
     let _filename = get_filename(path)?;
+    let absolute_path = std::path::absolute(path)?;
 
-    // Check file existence and permissions
-    let file_info = check_file_status(path)?;
+    // Check file existence and permissions on what the path resolves to now
+    let mut target = PinnedTarget::resolve(&absolute_path, symlinks)?;
+    let file_info = check_file_status(&mut target, helper)?;
+    for rule in edit_rules(file_path, &file_info) {
+        rule?;
+    }
 
     // Create temporary files using the editor module
-    let (tmp_file_path, tmp_copy_path) = create_secure_temp_copy(path, tmp_dir)?;
+    let (tmp_file_path, tmp_copy_path) = create_secure_temp_copy(&absolute_path, tmp_dir)?;
 
     // If file exists, copy its content to temporary file
     if file_info.exists {
-        // Check if user is not the owner of the file
-        if file_info.is_owned_by_user {
-            return Err(cannot_edit_own_file(file_path).into());
-        }
-
-        // Check if file is not a directory
-        if file_info.is_directory {
-            return Err(not_regular_file(file_path).into());
-        }
-
-        // Check if file is not both readable and writable by user
-        if file_info.readable && file_info.writable {
-            return Err(cannot_edit_readable_writable_file(file_path).into());
-        }
-
-        let use_doas = !file_info.readable;
-        copy_original_content(path, &tmp_file_path, use_doas)?;
+        // Copy the content only, so the temporary file stays 0600
+        let content = target.read(file_info.readable, helper)?;
+        fs::write(&tmp_file_path, content)?;
 
         // Create a copy for comparison
         create_comparison_copy(&tmp_file_path, &tmp_copy_path)?;
     }
 
-    // Open the file with editor
-    open_file_with_editor(&tmp_file_path, editor)?;
-
-    // Check if this is a doas config file and validate if needed
-    if is_doas_config_file(file_path) {
-        validate_doas_config(&tmp_file_path, editor)?;
-    }
+    Ok(PreparedFile {
+        file_path: file_path.to_string(),
+        note_path: validation_note_path(&absolute_path, tmp_dir),
+        absolute_path,
+        file_info,
+        tmp_file_path,
+        tmp_copy_path,
+        target,
+    })
+}
 
-    // Compare files and write back if changed
-    if !files_match(&tmp_file_path, &tmp_copy_path)? {
-        write_file_back(&tmp_file_path, path, file_info.writable)?;
-    } else {
-        println!("doasedit: {}: unchanged", file_path);
-    }
+/// Validate, review and write back a file the editor has been closed on
+///
+/// The editor is reopened on this file alone whenever the user asks to edit
+/// it again.
+pub fn finish_file(
+    prepared: &mut PreparedFile,
+    editor: Option<&EditorCommand>,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> Result<FileOutcome> {
+    let file_path = prepared.file_path.as_str();
+    let path = Path::new(file_path);
+    let file_info = &prepared.file_info;
+    let tmp_file_path = &prepared.tmp_file_path;
+    let tmp_copy_path = &prepared.tmp_copy_path;
+
+    let mut reopen_editor = false;
+    let mut validation_overridden = false;
+    loop {
+        // Open the file with editor
+        if reopen_editor {
+            open_file_with_editor(tmp_file_path, &editor_or_default(editor)?)?;
+        }
+        reopen_editor = true;
+
+        // Validate the edited file if a validator applies to the target
+        if let Some(validator) = options.validators.find(&prepared.absolute_path) {
+            validation_overridden = run_validator(
+                validator,
+                &prepared.absolute_path,
+                tmp_file_path,
+                &prepared.note_path,
+                editor,
+                helper,
+            )?;
+        }
 
-    Ok(())
-}
+        // Compare files and write back if changed
+        if files_match(tmp_file_path, tmp_copy_path)? {
+            println!("doasedit: {}: unchanged", file_path);
+            return Ok(FileOutcome::Unchanged);
+        }
 
-/// Write the modified content back to the original file
-pub fn write_file_back(tmp_file_path: &Path, original_path: &Path, writable: bool) -> Result<()> {
-    if writable {
-        fs::copy(tmp_file_path, original_path)?;
-    } else {
-        // Try with doas (with retry for password)
-        let mut success = false;
-        for attempt in 0..3 {
-            let output = Command::new("doas")
-                .arg("dd")
-                .arg("status=none")
-                .arg(format!("if={}", tmp_file_path.display()))
-                .arg(format!("of={}", original_path.display()))
-                .output()
-                .map_err(|_| doas_unavailable())?;
-
-            if output.status.success() {
-                success = true;
-                break;
+        if options.review {
+            match review_changes(tmp_copy_path, tmp_file_path, file_path)? {
+                ReviewChoice::Write => {}
+                ReviewChoice::EditAgain => continue,
+                ReviewChoice::Discard => {
+                    println!("doasedit: {}: changes discarded", file_path);
+                    return Ok(FileOutcome::Discarded);
+                }
             }
+        }
 
-            // If this is the third attempt, return error
-            if attempt == 2 {
-                return Err(three_incorrect_password_attempts());
+        // Make sure nobody changed the target while the editor was open
+        let current = prepared.target.read(file_info.readable, helper)?;
+        match check_concurrent_change(file_path, &current, tmp_copy_path, tmp_file_path)? {
+            ConcurrentChange::None | ConcurrentChange::KeepMine => {}
+            ConcurrentChange::Merged => {
+                // Validate and review the merged result without reopening the editor
+                reopen_editor = false;
+                continue;
+            }
+            ConcurrentChange::EditConflicts => continue,
+            ConcurrentChange::KeepTheirs => {
+                println!("doasedit: {}: kept the current version", file_path);
+                return Ok(FileOutcome::KeptCurrent);
             }
         }
 
-        if !success {
-            return Err(three_incorrect_password_attempts());
+        // Refuse to take away the user's own way back to root
+        let edited = fs::read(tmp_file_path)?;
+        if let Some(reason) = check_lockout(&prepared.absolute_path, &current, &edited, helper)? {
+            let error = lockout(path, &reason);
+            if options.allow_lockout {
+                eprintln!("doasedit: {}: {}; writing anyway", file_path, reason);
+            } else if !can_prompt() {
+                return Err(error);
+            } else {
+                eprintln!("doasedit: {}", error);
+                let input = read_user_input("(E)dit again, (A)bort: [E/a]? ")?;
+                if input.trim().eq_ignore_ascii_case("a") {
+                    return Err(error);
+                }
+                continue;
+            }
         }
+
+        write_file_back(
+            tmp_file_path,
+            path,
+            &mut prepared.target,
+            file_info.writable,
+            helper,
+            options,
+        )?;
+        return Ok(FileOutcome::Written {
+            validation_overridden,
+        });
+    }
+}
+
+/// Write the modified content back to the original file
+pub fn write_file_back(
+    tmp_file_path: &Path,
+    original_path: &Path,
+    target: &mut PinnedTarget,
+    writable: bool,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> Result<()> {
+    let content = fs::read(tmp_file_path)?;
+
+    // A signal must not cut the write-back short
+    let _critical = CriticalSection::enter();
+    let outcome = target.write(writable, content, helper, &options.backup)?;
+
+    if let Some(backup) = outcome.backup {
+        println!(
+            "doasedit: {}: previous version saved as {}",
+            original_path.display(),
+            backup.display()
+        );
+    }
+
+    for failure in outcome.failures {
+        eprintln!(
+            "doasedit: {}: could not preserve {}",
+            original_path.display(),
+            failure
+        );
     }
 
     Ok(())
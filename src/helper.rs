@@ -0,0 +1,435 @@
+use crate::atomic::{write_with_backup, WriteOutcome};
+use crate::backend::PrivilegeBackend;
+use crate::backup::BackupPolicy;
+use crate::error::{
+    backend_spawn_failed, helper_protocol_error, helper_request_failed, no_terminal,
+    three_incorrect_password_attempts, DoaseditError, Result,
+};
+use crate::metadata::property_name;
+use crate::protocol::{read_frame, write_frame, Request, Response, StatInfo};
+use crate::resolve::{resolve, Identity, SymlinkPolicy, Target};
+use crate::signals::check_interrupted;
+use nix::fcntl::OFlag;
+use nix::sys::stat::SFlag;
+use nix::unistd::geteuid;
+use nix::unistd::pipe2;
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+/// Hidden command line flag that starts the privileged side
+pub const HELPER_FLAG: &str = "--privileged-helper";
+
+/// Added after `HELPER_FLAG` when the helper talks over `HELPER_FDS` instead
+/// of stdin and stdout
+pub const HELPER_FDS_FLAG: &str = "--fds";
+
+/// Descriptors requests arrive on and responses leave by, for backends that
+/// keep stdin and stdout for themselves
+const HELPER_FDS: (RawFd, RawFd) = (3, 4);
+
+/// Output of a validation command run by the helper
+#[derive(Debug)]
+pub struct ValidationOutput {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// A target the helper has resolved and holds open
+///
+/// Reads and writes through it reach the file the path led to when it was
+/// opened, whatever happens to the path afterwards.
+#[derive(Debug)]
+pub struct Handle {
+    id: u32,
+    /// The path it was opened for, for messages
+    path: PathBuf,
+    pub identity: Identity,
+}
+
+/// Client side of the privileged helper
+///
+/// The helper is started lazily through the privilege backend on the first
+/// privileged operation, so a session that never needs root never prompts for
+/// a password, and one that does prompts only once.
+pub struct Helper {
+    backend: Box<dyn PrivilegeBackend>,
+    connection: Option<Connection>,
+}
+
+struct Connection {
+    child: Child,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+}
+
+impl Helper {
+    pub fn new(backend: Box<dyn PrivilegeBackend>) -> Helper {
+        Helper {
+            backend,
+            connection: None,
+        }
+    }
+
+    /// Resolve a target as root and keep it open for reading and writing,
+    /// or `None` if its directory does not exist
+    pub fn open(&mut self, path: &Path, symlinks: SymlinkPolicy) -> Result<Option<Handle>> {
+        match self.request(Request::Open {
+            path: path.to_path_buf(),
+            symlinks,
+        })? {
+            Response::Opened { handle, identity } => Ok(Some(Handle {
+                id: handle,
+                path: path.to_path_buf(),
+                identity,
+            })),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Get an opened file's metadata as root, or `None` if it does not exist
+    pub fn stat(&mut self, handle: &Handle) -> Result<Option<StatInfo>> {
+        let command = format!("stat {}", handle.path.display());
+        match self.request_as(Request::Stat { handle: handle.id }, &command)? {
+            Response::Stat(info) => Ok(info),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Read an opened file as root, or `None` if it does not exist
+    pub fn read(&mut self, handle: &Handle) -> Result<Option<Vec<u8>>> {
+        let command = format!("read {}", handle.path.display());
+        match self.request_as(Request::Read { handle: handle.id }, &command)? {
+            Response::Data(data) => Ok(Some(data)),
+            Response::NotFound => Ok(None),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Back up and replace an opened file's content as root
+    pub fn write(
+        &mut self,
+        handle: &Handle,
+        data: Vec<u8>,
+        backup: &BackupPolicy,
+    ) -> Result<WriteOutcome> {
+        let command = format!("write {}", handle.path.display());
+        let request = Request::Write {
+            handle: handle.id,
+            data,
+            backup: backup.clone(),
+        };
+        match self.request_as(request, &command)? {
+            Response::Written { backup, failures } => Ok(WriteOutcome { backup, failures }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Run a validation command as root
+    pub fn validate(&mut self, argv: Vec<OsString>) -> Result<ValidationOutput> {
+        match self.request(Request::Validate { argv })? {
+            Response::Validated {
+                success,
+                stdout,
+                stderr,
+            } => Ok(ValidationOutput {
+                success,
+                stdout,
+                stderr,
+            }),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let command = request.describe();
+        self.request_as(request, &command)
+    }
+
+    /// Send a request, naming it `command` if it fails
+    fn request_as(&mut self, request: Request, command: &str) -> Result<Response> {
+        let connection = self.connection()?;
+        write_frame(&mut connection.writer, &request.encode())?;
+
+        let frame = read_frame(&mut connection.reader)?
+            .ok_or_else(|| helper_protocol_error("helper exited unexpectedly"))?;
+        match Response::decode(&frame)? {
+            Response::Error(msg) => Err(helper_request_failed(command, &msg)),
+            response => Ok(response),
+        }
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(self.start()?);
+        }
+        Ok(self
+            .connection
+            .as_mut()
+            .expect("connection was just started"))
+    }
+
+    /// Start the helper, giving the user three chances to authenticate
+    fn start(&self) -> Result<Connection> {
+        let exe = env::current_exe()?;
+        // Without a terminal the password could not be asked for
+        if self.backend.authenticates_on_stdin() && !io::stdin().is_terminal() {
+            return Err(no_terminal());
+        }
+
+        for _ in 0..3 {
+            let (mut child, writer, reader) = self
+                .spawn(&exe)
+                .map_err(|e| backend_spawn_failed(self.backend.name(), e))?;
+            let writer = BufWriter::new(writer);
+            let mut reader = BufReader::new(reader);
+
+            // The helper announces itself once authentication succeeded
+            match read_frame(&mut reader)? {
+                Some(frame) => match Response::decode(&frame)? {
+                    Response::Ready { euid: 0 } => {
+                        return Ok(Connection {
+                            child,
+                            writer,
+                            reader,
+                        })
+                    }
+                    Response::Ready { .. } => {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        return Err(helper_protocol_error("helper is not running as root"));
+                    }
+                    other => return Err(unexpected(other)),
+                },
+                None => {
+                    let _ = child.wait();
+                    // Ctrl-C at the password prompt ends the backend; do not ask again
+                    check_interrupted()?;
+                }
+            }
+        }
+
+        Err(three_incorrect_password_attempts(self.backend.name()))
+    }
+
+    /// Start the backend on the helper, returning the ends requests are
+    /// written to and responses read from
+    ///
+    /// Normally these are the helper's stdin and stdout. A backend that reads
+    /// the password from stdin keeps the terminal there, and the helper gets
+    /// pipes as `HELPER_FDS` instead.
+    fn spawn(&self, exe: &Path) -> io::Result<(Child, File, File)> {
+        if !self.backend.authenticates_on_stdin() {
+            let mut child = self
+                .backend
+                .command(exe.as_os_str(), &[HELPER_FLAG.as_ref()])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .spawn()?;
+            let writer = OwnedFd::from(child.stdin.take().expect("stdin is piped"));
+            let reader = OwnedFd::from(child.stdout.take().expect("stdout is piped"));
+            return Ok((child, File::from(writer), File::from(reader)));
+        }
+
+        let (requests, request_end) = pipe2(OFlag::O_CLOEXEC)?;
+        let (response_end, responses) = pipe2(OFlag::O_CLOEXEC)?;
+        let (request_fd, response_fd) = (requests.as_raw_fd(), responses.as_raw_fd());
+
+        let mut command = self.backend.command(
+            exe.as_os_str(),
+            &[HELPER_FLAG.as_ref(), HELPER_FDS_FLAG.as_ref()],
+        );
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                // Move both ends out of the way first, in case either already
+                // sits on one of the target descriptors
+                let request_fd = libc::fcntl(request_fd, libc::F_DUPFD_CLOEXEC, 10);
+                let response_fd = libc::fcntl(response_fd, libc::F_DUPFD_CLOEXEC, 10);
+                if request_fd < 0
+                    || response_fd < 0
+                    || libc::dup2(request_fd, HELPER_FDS.0) < 0
+                    || libc::dup2(response_fd, HELPER_FDS.1) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.stderr(Stdio::inherit()).spawn()?;
+
+        // The helper's ends are its own now; holding them would keep the
+        // pipes open after it exits
+        drop(requests);
+        drop(responses);
+        Ok((child, File::from(request_end), File::from(response_end)))
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            let _ = write_frame(&mut connection.writer, &Request::Shutdown.encode());
+            drop(connection.writer);
+            let _ = connection.child.wait();
+        }
+    }
+}
+
+fn unexpected(response: Response) -> DoaseditError {
+    helper_protocol_error(&format!("unexpected response {:?}", response))
+}
+
+/// Serve requests on stdin/stdout until told to stop
+///
+/// This is the privileged side, running as root under the privilege backend.
+/// Opened targets are kept until the helper exits; a handle is an index into
+/// them.
+///
+/// With `on_fds` the requests and responses use `HELPER_FDS` instead.
+pub fn run_helper(on_fds: bool) -> Result<()> {
+    let mut targets = Vec::new();
+    let (input, output): (Box<dyn Read>, Box<dyn Write>) = if on_fds {
+        // SAFETY: the client set these up for the helper and nothing else
+        // uses them
+        unsafe {
+            (
+                Box::new(File::from_raw_fd(HELPER_FDS.0)),
+                Box::new(File::from_raw_fd(HELPER_FDS.1)),
+            )
+        }
+    } else {
+        (Box::new(io::stdin().lock()), Box::new(io::stdout().lock()))
+    };
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+
+    write_frame(
+        &mut writer,
+        &Response::Ready {
+            euid: geteuid().as_raw(),
+        }
+        .encode(),
+    )?;
+
+    while let Some(frame) = read_frame(&mut reader)? {
+        let response = match Request::decode(&frame)? {
+            Request::Shutdown => break,
+            request => handle_request(request, &mut targets),
+        };
+        write_frame(&mut writer, &response.encode())?;
+    }
+
+    Ok(())
+}
+
+fn handle_request(request: Request, targets: &mut Vec<Target>) -> Response {
+    let result = match request {
+        Request::Open { path, symlinks } => serve_open(&path, symlinks, targets),
+        Request::Stat { handle } => lookup(targets, handle).and_then(serve_stat),
+        Request::Read { handle } => lookup(targets, handle).and_then(serve_read),
+        Request::Write {
+            handle,
+            data,
+            backup,
+        } => lookup(targets, handle).and_then(|target| serve_write(target, &data, &backup)),
+        Request::Validate { argv } => serve_validate(&argv),
+        Request::Shutdown => Ok(Response::Done),
+    };
+
+    result.unwrap_or_else(|e| Response::Error(e.to_string()))
+}
+
+fn serve_stat(target: &Target) -> io::Result<Response> {
+    let Some(stat) = target.stat()? else {
+        return Ok(Response::Stat(None));
+    };
+    let kind = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT;
+    let is_file = kind == SFlag::S_IFREG;
+    // Only regular files have metadata to carry over
+    let preserved = if is_file {
+        target.metadata()?.map_or_else(Vec::new, |metadata| {
+            metadata
+                .xattrs
+                .iter()
+                .map(|(name, _)| property_name(name))
+                .collect()
+        })
+    } else {
+        Vec::new()
+    };
+
+    Ok(Response::Stat(Some(StatInfo {
+        is_file,
+        is_dir: kind == SFlag::S_IFDIR,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        mode: stat.st_mode & 0o7777,
+        preserved,
+    })))
+}
+
+fn serve_open(
+    path: &Path,
+    symlinks: SymlinkPolicy,
+    targets: &mut Vec<Target>,
+) -> io::Result<Response> {
+    let target = match resolve(path, symlinks) {
+        Ok(target) => target,
+        // Some directory on the way does not exist
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Response::NotFound),
+        Err(e) => return Err(e),
+    };
+    let identity = target.identity()?;
+    targets.push(target);
+    Ok(Response::Opened {
+        handle: (targets.len() - 1) as u32,
+        identity,
+    })
+}
+
+fn lookup(targets: &[Target], handle: u32) -> io::Result<&Target> {
+    targets
+        .get(handle as usize)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown handle"))
+}
+
+fn serve_read(target: &Target) -> io::Result<Response> {
+    Ok(match target.read()? {
+        Some(data) => Response::Data(data),
+        None => Response::NotFound,
+    })
+}
+
+fn serve_write(target: &Target, data: &[u8], policy: &BackupPolicy) -> io::Result<Response> {
+    let outcome = write_with_backup(target, data, policy)?;
+    Ok(Response::Written {
+        backup: outcome.backup,
+        failures: outcome.failures,
+    })
+}
+
+fn serve_validate(argv: &[OsString]) -> io::Result<Response> {
+    let (program, args) = argv
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+
+    Ok(Response::Validated {
+        success: output.status.success(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
@@ -0,0 +1,507 @@
+use crate::access::Credentials;
+use crate::doas_conf::{self, DOAS_CONF};
+use crate::error::Result;
+use crate::helper::{Helper, HELPER_FLAG};
+use crate::sudoers::{Sudoers, SUDOERS};
+use crate::utils::glob_match;
+use crate::validate::{assemble_doas_config, assemble_sudoers, read_config_file};
+use nix::unistd::{gethostname, Gid, Group, Uid, User};
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Directories PAM modules named without a path are loaded from
+const PAM_MODULE_DIRS: [&str; 8] = [
+    "/lib/security",
+    "/lib64/security",
+    "/usr/lib/security",
+    "/usr/lib64/security",
+    "/lib/x86_64-linux-gnu/security",
+    "/usr/lib/x86_64-linux-gnu/security",
+    "/lib/aarch64-linux-gnu/security",
+    "/usr/lib/aarch64-linux-gnu/security",
+];
+
+/// The user running doasedit, as privilege configurations name them
+pub struct Caller {
+    pub name: String,
+    pub uid: u32,
+    /// Names and ids of the user's groups
+    pub groups: Vec<(String, u32)>,
+    pub host: String,
+}
+
+impl Caller {
+    pub fn current() -> io::Result<Caller> {
+        let credentials = Credentials::current()?;
+        let name = User::from_uid(Uid::from_raw(credentials.uid))?
+            .map_or_else(|| credentials.uid.to_string(), |user| user.name);
+        let groups = credentials
+            .groups
+            .iter()
+            .map(|&gid| {
+                let name = Group::from_gid(Gid::from_raw(gid))
+                    .ok()
+                    .flatten()
+                    .map_or_else(|| gid.to_string(), |group| group.name);
+                (name, gid)
+            })
+            .collect();
+
+        Ok(Caller {
+            name,
+            uid: credentials.uid,
+            groups,
+            host: gethostname()?.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Whether `spec`, a user name or numeric uid, names the caller
+    pub fn is_user(&self, spec: &str) -> bool {
+        spec == self.name || spec.parse() == Ok(self.uid)
+    }
+
+    /// Whether `spec`, a group name or numeric gid, is one of the caller's
+    pub fn in_group(&self, spec: &str) -> bool {
+        self.groups
+            .iter()
+            .any(|(name, gid)| spec == name || spec.parse() == Ok(*gid))
+    }
+}
+
+/// The kinds of configuration the guard understands
+enum Config {
+    Doas,
+    Sudoers,
+    Pam,
+}
+
+fn config_kind(path: &Path) -> Option<Config> {
+    let path = path.to_string_lossy();
+    let is = |patterns: &[&str]| patterns.iter().any(|pattern| glob_match(pattern, &path));
+
+    if is(&[DOAS_CONF, "/etc/doas.d/*"]) {
+        Some(Config::Doas)
+    } else if is(&["/etc/sudoers", "/etc/sudoers.d/*"]) {
+        Some(Config::Sudoers)
+    } else if is(&["/etc/pam.conf", "/etc/pam.d/*"]) {
+        Some(Config::Pam)
+    } else {
+        None
+    }
+}
+
+/// Why replacing `old` with `new` at `path` would lock the user out, if it
+/// would
+///
+/// For doas.conf and sudoers, the user must keep whatever of the following
+/// the current content gave them: running doasedit's privileged helper as
+/// root, and running anything as root at all. A doas.conf or sudoers file is
+/// judged within the whole configuration it is part of, the other files read
+/// through `helper` if need be. A PAM file must not go from working to one
+/// that cannot succeed, judged on its own.
+pub fn check_lockout(
+    path: &Path,
+    old: &[u8],
+    new: &[u8],
+    helper: &mut Helper,
+) -> Result<Option<String>> {
+    let Some(kind) = config_kind(path) else {
+        return Ok(None);
+    };
+    let old = String::from_utf8_lossy(old);
+    let new = String::from_utf8_lossy(new);
+
+    if let Config::Pam = kind {
+        let is_pam_conf = path == Path::new("/etc/pam.conf");
+        return Ok(match pam_problem(&old, is_pam_conf) {
+            Some(_) => None,
+            None => pam_problem(&new, is_pam_conf),
+        });
+    }
+
+    let caller = Caller::current()?;
+    let helper_command = vec![
+        env::current_exe()?.to_string_lossy().into_owned(),
+        HELPER_FLAG.to_string(),
+    ];
+    let (backend, before, after) = match kind {
+        Config::Doas => (
+            "doas",
+            doas_access(
+                &assemble_doas_config(old.into_owned(), path, helper)?,
+                &caller,
+                &helper_command,
+            ),
+            doas_access(
+                &assemble_doas_config(new.into_owned(), path, helper)?,
+                &caller,
+                &helper_command,
+            ),
+        ),
+        _ => {
+            let mut read = |path: &Path| read_config_file(path, helper);
+            let main = Path::new(SUDOERS);
+            (
+                "sudo",
+                sudoers_access(
+                    &assemble_sudoers(main, old.into_owned(), path, &mut read)?,
+                    &caller,
+                    &helper_command,
+                ),
+                sudoers_access(
+                    &assemble_sudoers(main, new.into_owned(), path, &mut read)?,
+                    &caller,
+                    &helper_command,
+                ),
+            )
+        }
+    };
+
+    Ok(if before.helper && !after.helper {
+        Some(format!(
+            "{} could no longer run doasedit as root through {}",
+            caller.name, backend
+        ))
+    } else if before.root && !after.root {
+        Some(format!(
+            "{} would no longer have root through {}",
+            caller.name, backend
+        ))
+    } else {
+        None
+    })
+}
+
+/// What a configuration lets the caller do
+struct Access {
+    /// Run doasedit's privileged helper as root
+    helper: bool,
+    /// Run anything as root, the helper included
+    root: bool,
+}
+
+fn doas_access(files: &[(Option<PathBuf>, String)], caller: &Caller, helper: &[String]) -> Access {
+    match doas_conf::parse_files(files) {
+        Ok(rules) => {
+            let helper = doas_conf::permits_root(&rules, caller, helper);
+            Access {
+                helper,
+                root: helper || doas_conf::grants_root(&rules, caller),
+            }
+        }
+        // doas refuses everything when its configuration does not parse
+        Err(_) => Access {
+            helper: false,
+            root: false,
+        },
+    }
+}
+
+fn sudoers_access(content: &str, caller: &Caller, helper: &[String]) -> Access {
+    let sudoers = Sudoers::parse(content);
+    let helper = sudoers.permits_root(caller, helper);
+    Access {
+        helper,
+        root: helper || sudoers.grants_root(caller),
+    }
+}
+
+/// Why a PAM configuration could never let anyone through, if it could not
+///
+/// Looks for required modules that do not exist, included files that do not
+/// exist, and a required `pam_deny` in the auth or account stack before any
+/// module could succeed.
+fn pam_problem(content: &str, is_pam_conf: bool) -> Option<String> {
+    let mut decided = Vec::new();
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut fields = line.split_whitespace();
+        if is_pam_conf {
+            fields.next();
+        }
+        let Some(kind) = fields.next() else {
+            continue;
+        };
+
+        if kind == "@include" {
+            let file = fields.next().unwrap_or("");
+            if !Path::new("/etc/pam.d").join(file).exists() {
+                return Some(format!("included file {} does not exist", file));
+            }
+            continue;
+        }
+
+        // A leading `-` makes a missing module silently skipped
+        let optional = kind.starts_with('-');
+        let kind = kind.trim_start_matches('-');
+
+        let mut control = fields.next().unwrap_or("").to_string();
+        if control.starts_with('[') {
+            // `[success=1 default=ignore]` may span several words
+            while !control.ends_with(']') {
+                let Some(word) = fields.next() else {
+                    break;
+                };
+                control.push(' ');
+                control.push_str(word);
+            }
+        }
+        let module = fields.next().unwrap_or("");
+
+        match control.as_str() {
+            "include" | "substack" => {
+                if !Path::new("/etc/pam.d").join(module).exists() {
+                    return Some(format!("included file {} does not exist", module));
+                }
+                continue;
+            }
+            "required" | "requisite" => {}
+            control => {
+                // A module that can succeed on its own, or jump over what
+                // follows, may skip a later pam_deny
+                if control == "sufficient" || control.contains("success=") {
+                    decided.push(kind.to_string());
+                }
+                continue;
+            }
+        }
+
+        if !optional && !module_exists(module) {
+            return Some(format!(
+                "{} module {} does not exist, so every {} would fail",
+                kind, module, kind
+            ));
+        }
+
+        let denies = module.rsplit('/').next() == Some("pam_deny.so");
+        if denies && matches!(kind, "auth" | "account") && !decided.iter().any(|k| k == kind) {
+            return Some(format!("pam_deny.so fails every {} request", kind));
+        }
+    }
+
+    None
+}
+
+fn module_exists(module: &str) -> bool {
+    if module.starts_with('/') {
+        return Path::new(module).exists();
+    }
+    PAM_MODULE_DIRS
+        .iter()
+        .any(|dir| Path::new(dir).join(module).exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn alice() -> Caller {
+        Caller {
+            name: "alice".to_string(),
+            uid: 1000,
+            groups: vec![("alice".to_string(), 1000), ("wheel".to_string(), 10)],
+            host: "box".to_string(),
+        }
+    }
+
+    fn helper_command() -> Vec<String> {
+        vec!["/usr/bin/doasedit".to_string(), HELPER_FLAG.to_string()]
+    }
+
+    fn doas(files: &[(Option<&str>, &str)]) -> (bool, bool) {
+        let files: Vec<_> = files
+            .iter()
+            .map(|(file, content)| (file.map(PathBuf::from), content.to_string()))
+            .collect();
+        let access = doas_access(&files, &alice(), &helper_command());
+        (access.helper, access.root)
+    }
+
+    fn sudo(content: &str) -> (bool, bool) {
+        let access = sudoers_access(content, &alice(), &helper_command());
+        (access.helper, access.root)
+    }
+
+    #[test]
+    fn recognizes_guarded_files() {
+        let kind = |path: &str| match config_kind(Path::new(path)) {
+            Some(Config::Doas) => "doas",
+            Some(Config::Sudoers) => "sudoers",
+            Some(Config::Pam) => "pam",
+            None => "",
+        };
+        assert_eq!(kind("/etc/doas.conf"), "doas");
+        assert_eq!(kind("/etc/doas.d/10-admins.conf"), "doas");
+        assert_eq!(kind("/etc/sudoers"), "sudoers");
+        assert_eq!(kind("/etc/sudoers.d/alice"), "sudoers");
+        assert_eq!(kind("/etc/pam.conf"), "pam");
+        assert_eq!(kind("/etc/pam.d/sudo"), "pam");
+        assert_eq!(kind("/etc/doas.conf.bak"), "");
+        assert_eq!(kind("/etc/hosts"), "");
+    }
+
+    #[test]
+    fn doas_access_follows_the_last_matching_rule() {
+        let cases = [
+            ("permit alice", (true, true)),
+            ("permit nopass :wheel", (true, true)),
+            ("permit 1000", (true, true)),
+            ("permit :10", (true, true)),
+            ("permit bob", (false, false)),
+            ("permit alice as bob", (false, false)),
+            ("permit alice as root", (true, true)),
+            ("permit alice cmd /usr/bin/apt", (false, true)),
+            ("permit alice cmd /usr/bin/doasedit", (true, true)),
+            (
+                "permit alice cmd /usr/bin/doasedit args --privileged-helper",
+                (true, true),
+            ),
+            ("permit alice cmd /usr/bin/doasedit args", (false, true)),
+            ("permit :wheel\ndeny alice", (false, false)),
+            ("deny alice\npermit :wheel", (true, true)),
+            (
+                "permit alice\ndeny alice cmd /usr/bin/doasedit",
+                (false, true),
+            ),
+            ("permit alice\ndeny alice cmd /bin/sh", (true, true)),
+            ("", (false, false)),
+            // doas refuses everything when the file does not parse
+            ("permit alice\npermit", (false, false)),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(doas(&[(None, content)]), expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn doas_access_reads_fragments_after_the_main_file() {
+        let fragment = Some("/etc/doas.d/10-deny.conf");
+        assert_eq!(
+            doas(&[(Some(DOAS_CONF), "permit alice"), (None, "deny alice")]),
+            (false, false)
+        );
+        assert_eq!(
+            doas(&[(None, "deny alice"), (fragment, "permit :wheel")]),
+            (true, true)
+        );
+        assert_eq!(
+            doas(&[(None, "permit alice"), (fragment, "permit")]),
+            (false, false)
+        );
+    }
+
+    #[test]
+    fn sudoers_access_separates_the_helper_from_root() {
+        let cases = [
+            ("alice ALL=(ALL) ALL", (true, true)),
+            ("%wheel ALL=(ALL:ALL) NOPASSWD: ALL", (true, true)),
+            ("alice ALL=(ALL) /usr/bin/apt", (false, true)),
+            (
+                "alice ALL=(ALL) /usr/bin/doasedit --privileged-helper",
+                (true, true),
+            ),
+            ("alice ALL=(ALL) /usr/bin/doasedit \"\"", (false, true)),
+            ("alice ALL=(ALL) ALL, !/usr/bin/doasedit", (false, true)),
+            ("alice ALL=(bob) ALL", (false, false)),
+            (
+                "Defaults targetpw\n#includedir /etc/sudoers.d",
+                (false, false),
+            ),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(sudo(content), expected, "{:?}", content);
+        }
+    }
+
+    /// Access under the sudoers policy rooted at `main`, with `content` in
+    /// place of `target`
+    fn sudo_policy(main: &Path, target: &Path, content: &str) -> (bool, bool) {
+        let mut read = |path: &Path| match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        };
+        sudo(&assemble_sudoers(main, content.to_string(), target, &mut read).unwrap())
+    }
+
+    #[test]
+    fn sudoers_access_covers_included_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("sudoers");
+        let fragments = dir.path().join("sudoers.d");
+        let fragment = fragments.join("alice");
+        fs::create_dir(&fragments).unwrap();
+        let includes = format!("Defaults env_reset\n@includedir {}\n", fragments.display());
+        fs::write(&main, &includes).unwrap();
+        fs::write(&fragment, "alice ALL=(ALL) ALL\n").unwrap();
+
+        // Dropping the include loses the grant that lives in the fragment
+        assert_eq!(sudo_policy(&main, &main, &includes), (true, true));
+        assert_eq!(
+            sudo_policy(&main, &main, "Defaults env_reset\n"),
+            (false, false)
+        );
+        let legacy = format!("#includedir {}\n", fragments.display());
+        assert_eq!(sudo_policy(&main, &main, &legacy), (true, true));
+
+        // Emptying the fragment loses it too, unless it is granted elsewhere
+        assert_eq!(sudo_policy(&main, &fragment, ""), (false, false));
+        fs::write(&main, format!("%wheel ALL=(ALL) ALL\n{}", includes)).unwrap();
+        assert_eq!(sudo_policy(&main, &fragment, ""), (true, true));
+
+        // A later fragment can take the grant away again
+        let deny = "alice ALL=(ALL) ALL, !/usr/bin/doasedit\n";
+        assert_eq!(
+            sudo_policy(&main, &fragments.join("zz-deny"), deny),
+            (false, true)
+        );
+        assert_eq!(
+            sudo_policy(&main, &fragments.join("00-deny"), deny),
+            (true, true)
+        );
+    }
+
+    #[test]
+    fn pam_problem_finds_stacks_that_cannot_succeed() {
+        let dir = tempfile::tempdir().unwrap();
+        let deny = dir.path().join("pam_deny.so");
+        let unix = dir.path().join("pam_unix.so");
+        fs::write(&deny, "").unwrap();
+        fs::write(&unix, "").unwrap();
+        let (deny, unix) = (deny.display(), unix.display());
+
+        let working = format!("auth sufficient {}\nauth required {}\n", unix, deny);
+        assert_eq!(pam_problem(&working, false), None);
+        let jump = format!(
+            "auth [success=1 default=ignore] {}\nauth requisite {}\n",
+            unix, deny
+        );
+        assert_eq!(pam_problem(&jump, false), None);
+        let session = format!("session required {}\n", deny);
+        assert_eq!(pam_problem(&session, false), None);
+        let optional = "-auth required /nonexistent/pam_gone.so\n";
+        assert_eq!(pam_problem(optional, false), None);
+
+        let denied = format!("# comment\nauth required {}\n", deny);
+        assert_eq!(
+            pam_problem(&denied, false).as_deref(),
+            Some("pam_deny.so fails every auth request")
+        );
+        let pam_conf = format!("sudo account required {}\n", deny);
+        assert_eq!(
+            pam_problem(&pam_conf, true).as_deref(),
+            Some("pam_deny.so fails every account request")
+        );
+        assert_eq!(
+            pam_problem("auth required /nonexistent/pam_gone.so\n", false).as_deref(),
+            Some("auth module /nonexistent/pam_gone.so does not exist, so every auth would fail")
+        );
+        assert_eq!(
+            pam_problem("@include doasedit-test-missing\n", false).as_deref(),
+            Some("included file doasedit-test-missing does not exist")
+        );
+    }
+}
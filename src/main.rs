@@ -1,75 +1,410 @@
+mod access;
+mod atomic;
+mod backend;
+mod backup;
+mod cleanup;
+mod config;
+mod conflict;
+mod diff;
+mod doas_conf;
 mod editor;
 mod error;
+mod explain;
 mod file_handler;
+mod helper;
+mod lockout;
+mod metadata;
+mod protocol;
+mod recovery;
+mod resolve;
+mod review;
+mod signals;
+mod sudoers;
 mod utils;
+mod validate;
 
-use clap::Arg;
-use error::{doas_unavailable, root_user_not_allowed};
-use file_handler::process_file;
+use backend::select_backend;
+use backup::{BackupMode, BackupPolicy};
+use clap::{Arg, ArgAction};
+use cleanup::{sweep_stale_dirs, SessionDir};
+use config::load_config;
+use editor::EditorCommand;
+use error::{
+    io_error, nothing_to_recover, root_user_not_allowed, EXIT_FAILURE, EXIT_OK, EXIT_USAGE,
+};
+use file_handler::{
+    exit_code, print_summary, process_files, recover_file, write_from, ContentSource, EditOptions,
+    FileOutcome, FileReport,
+};
+use helper::{run_helper, Helper, HELPER_FDS_FLAG, HELPER_FLAG};
 use nix::unistd::geteuid;
-use std::process::Command;
-use tempfile::tempdir;
+use resolve::SymlinkPolicy;
+use signals::{handle_signals, ignore_terminal_signals};
+use std::path::Path;
+use validate::Registry;
 
 fn main() {
+    // The privileged side is the same binary started through the backend
+    if std::env::args().nth(1).as_deref() == Some(HELPER_FLAG) {
+        ignore_terminal_signals();
+        let on_fds = std::env::args().nth(2).as_deref() == Some(HELPER_FDS_FLAG);
+        if let Err(e) = run_helper(on_fds) {
+            eprintln!("doasedit: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let matches = clap::Command::new("doasedit")
         .version(env!("CARGO_PKG_VERSION"))
         .about(
             "A Rust implementation of doasedit - edit files as root using an unprivileged editor",
         )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .value_name("NAME")
+                .value_parser(["doas", "sudo", "run0", "su"])
+                .help("Privilege backend to use (default: from config, else first available)"),
+        )
+        .arg(
+            Arg::new("backup")
+                .long("backup")
+                .value_name("MODE")
+                .value_parser(["none", "simple", "numbered", "directory"])
+                .help("Save the previous version before writing (default: from config, else none)"),
+        )
+        .arg(
+            Arg::new("symlinks")
+                .long("symlinks")
+                .value_name("POLICY")
+                .value_parser(["refuse", "follow", "replace-link"])
+                .help(
+                    "What to do when a file is a symbolic link (default: from config, else follow)",
+                ),
+        )
+        .arg(
+            Arg::new("review")
+                .long("review")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-review")
+                .help("Show a diff and ask for confirmation before writing"),
+        )
+        .arg(
+            Arg::new("no-review")
+                .long("no-review")
+                .action(ArgAction::SetTrue)
+                .help("Write without showing a diff, even if the config asks for one"),
+        )
+        .arg(
+            Arg::new("together")
+                .long("together")
+                .action(ArgAction::SetTrue)
+                .overrides_with("separately")
+                .help("Open all files in a single editor session"),
+        )
+        .arg(
+            Arg::new("separately")
+                .long("separately")
+                .action(ArgAction::SetTrue)
+                .help("Open the editor once per file"),
+        )
+        .arg(
+            Arg::new("keep-going")
+                .short('k')
+                .long("keep-going")
+                .action(ArgAction::SetTrue)
+                .help("Carry on with the remaining files after one fails, then print a summary"),
+        )
+        .arg(
+            Arg::new("i-know-what-im-doing")
+                .long("i-know-what-im-doing")
+                .action(ArgAction::SetTrue)
+                .help("Write doas, sudoers or PAM changes that would lock you out of root"),
+        )
+        .arg(
+            Arg::new("stdin")
+                .long("stdin")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["from", "together", "separately"])
+                .help("Write the content read from standard input instead of opening an editor"),
+        )
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .value_name("FILE")
+                .conflicts_with_all(["together", "separately"])
+                .help("Write the content of FILE instead of opening an editor"),
+        )
+        .arg(
+            Arg::new("recover")
+                .long("recover")
+                .value_name("PATH")
+                .num_args(0..=1)
+                .conflicts_with_all(["files", "stdin", "from"])
+                .help("List edits that could not be written, or review and apply the one for PATH"),
+        )
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .value_name("PATH")
+                .conflicts_with_all(["files", "recover", "stdin", "from"])
+                .help("Explain whether and how PATH may be edited, without editing it"),
+        )
         .arg(
             Arg::new("files")
                 .help("Files to edit")
-                .required(true)
+                .required_unless_present_any(["recover", "explain"])
                 .num_args(1..),
         )
         .get_matches();
 
+    // New content given on the command line replaces a single file
+    let writes_content = matches.get_flag("stdin") || matches.contains_id("from");
+    if writes_content
+        && matches
+            .get_many::<String>("files")
+            .map_or(0, Iterator::count)
+            != 1
+    {
+        eprintln!("doasedit: --stdin and --from take exactly one file");
+        std::process::exit(EXIT_USAGE);
+    }
+
     // Check if running as root
     if geteuid().is_root() {
-        eprintln!("doasedit: {}", root_user_not_allowed().to_string());
-        std::process::exit(1);
+        let e = root_user_not_allowed();
+        eprintln!("doasedit: {}", e);
+        std::process::exit(e.exit_code());
     }
 
-    // Check if doas is available
-    match Command::new("which").arg("doas").output() {
-        Ok(output) if output.status.success() => {}
-        _ => {
-            eprintln!("doasedit: {}", doas_unavailable().to_string());
-            std::process::exit(1);
+    // Temporary copies must not outlive the run, whatever ends it
+    if let Err(e) = handle_signals() {
+        eprintln!("doasedit: cannot handle signals: {}", e);
+    }
+    sweep_stale_dirs();
+
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("doasedit: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    // Listing saved edits needs neither privileges nor an editor
+    let recover = if matches.contains_id("recover") {
+        match matches.get_one::<String>("recover") {
+            Some(path) => Some(path.clone()),
+            None => {
+                list_saved_edits();
+                return;
+            }
         }
+    } else {
+        None
+    };
+
+    let symlinks = matches
+        .get_one::<String>("symlinks")
+        .and_then(|policy| SymlinkPolicy::parse(policy))
+        .or(config.symlinks)
+        .unwrap_or_default();
+
+    let mut backup = BackupPolicy::default();
+    if let Some(mode) = config.backup {
+        backup.mode = mode;
+    }
+    if let Some(mode) = matches
+        .get_one::<String>("backup")
+        .and_then(|mode| BackupMode::parse(mode))
+    {
+        backup.mode = mode;
+    }
+    if let Some(dir) = config.backup_dir {
+        backup.dir = dir;
     }
+    backup.keep = config.backup_keep.unwrap_or(0);
+    backup.max_age_days = config.backup_max_age.unwrap_or(0);
 
-    // Determine editor command
-    let editor = match editor::get_editor_command() {
-        Ok(editor) => editor,
+    // Pick the privilege backend and check that it is available
+    let requested_backend = matches
+        .get_one::<String>("backend")
+        .or(config.backend.as_ref());
+
+    // Explaining needs no editor and never starts the helper
+    if let Some(path) = matches.get_one::<String>("explain") {
+        let backend =
+            select_backend(requested_backend.map(String::as_str)).map(|backend| backend.name());
+        let allowed = explain::explain(
+            path,
+            backend,
+            symlinks,
+            &backup,
+            &Registry::with_config(&config.validators),
+        );
+        std::process::exit(if allowed { EXIT_OK } else { EXIT_FAILURE });
+    }
+    let mut helper = match select_backend(requested_backend.map(String::as_str)) {
+        Ok(backend) => Helper::new(backend),
         Err(e) => {
             eprintln!("doasedit: {}", e);
-            std::process::exit(1);
+            std::process::exit(e.exit_code());
         }
     };
 
+    // A recovered edit is always shown before it is applied
+    let review = if matches.get_flag("review") || recover.is_some() {
+        true
+    } else if matches.get_flag("no-review") {
+        false
+    } else {
+        config.review.unwrap_or(false)
+    };
+
+    let options = EditOptions {
+        backup,
+        review,
+        validators: Registry::with_config(&config.validators),
+        keep_going: matches.get_flag("keep-going"),
+        symlinks,
+        allow_lockout: matches.get_flag("i-know-what-im-doing"),
+    };
+
+    let files: Vec<&str> = matches
+        .get_many::<String>("files")
+        .map(|files| files.map(String::as_str).collect())
+        .unwrap_or_default();
+
+    // Scripts hand over the new content instead of editing
+    let source = if matches.get_flag("stdin") {
+        Some(ContentSource::Stdin)
+    } else {
+        matches
+            .get_one::<String>("from")
+            .map(|file| ContentSource::File(file.into()))
+    };
+
+    // Anything but writing from a source edits, so an editor is needed; it is
+    // resolved before the session directory exists so failing leaves nothing
+    // behind
+    let editor = match source {
+        Some(_) => None,
+        None => match editor::get_editor_command() {
+            Ok(editor) => Some(editor),
+            Err(e) => {
+                eprintln!("doasedit: {}", e);
+                std::process::exit(e.exit_code());
+            }
+        },
+    };
+
     // Create temporary directory
-    let tmp_dir = match tempdir() {
+    let tmp_dir = match SessionDir::create() {
         Ok(dir) => dir,
         Err(e) => {
             eprintln!("doasedit: Error creating temporary directory: {}", e);
-            std::process::exit(1);
+            std::process::exit(EXIT_FAILURE);
         }
     };
 
-    let mut _exit_code = 1;
-
-    if let Some(files) = matches.get_many::<String>("files") {
-        for file_path in files {
-            match process_file(file_path, &editor, tmp_dir.path()) {
-                Ok(_) => {
-                    _exit_code = 0;
-                }
-                Err(e) => {
-                    eprintln!("doasedit: {}", e.to_string());
-                    std::process::exit(1);
-                }
-            }
+    if let Some(source) = source {
+        let file_path = files[0];
+        let result = write_from(file_path, &source, tmp_dir.path(), &mut helper, &options);
+        if let Err(e) = &result {
+            eprintln!("doasedit: {}", e);
+        }
+        let reports = [FileReport {
+            file_path: file_path.to_string(),
+            result: Some(result),
+        }];
+        let code = exit_code(&reports);
+        drop(helper);
+        drop(tmp_dir);
+        std::process::exit(code);
+    }
+
+    // Everything from here on edits
+    let editor = editor.expect("an editor is resolved unless writing from a source");
+
+    if let Some(path) = recover {
+        let result = recover_saved_edit(&path, &editor, tmp_dir.path(), &mut helper, &options);
+        if let Err(e) = &result {
+            eprintln!("doasedit: {}", e);
+        }
+        let reports = [FileReport {
+            file_path: path,
+            result: Some(result),
+        }];
+        let code = exit_code(&reports);
+        drop(helper);
+        drop(tmp_dir);
+        std::process::exit(code);
+    }
+
+    // Editors known to take several files get them all at once by default
+    let together = if matches.get_flag("together") {
+        true
+    } else if matches.get_flag("separately") {
+        false
+    } else {
+        config
+            .together
+            .unwrap_or_else(|| editor.supports_multiple_files())
+    };
+
+    let reports = process_files(
+        &files,
+        &editor,
+        tmp_dir.path(),
+        &mut helper,
+        &options,
+        together,
+    );
+    if options.keep_going {
+        print_summary(&reports);
+    }
+
+    let code = exit_code(&reports);
+    // Shut the helper down and wipe the temporary files before exiting
+    drop(helper);
+    drop(tmp_dir);
+    std::process::exit(code);
+}
+
+/// Print the edits kept in the recovery store
+fn list_saved_edits() {
+    match recovery::list() {
+        Ok(entries) if entries.is_empty() => println!("doasedit: no saved edits"),
+        Ok(entries) => {
+            println!("doasedit: saved edits:");
+            recovery::print_entries(&entries);
+        }
+        Err(e) => {
+            eprintln!("doasedit: cannot read the recovery store: {}", e);
+            std::process::exit(EXIT_FAILURE);
         }
     }
 }
+
+/// Review and apply the newest saved edit of `path`
+fn recover_saved_edit(
+    path: &str,
+    editor: &EditorCommand,
+    tmp_dir: &Path,
+    helper: &mut Helper,
+    options: &EditOptions,
+) -> error::Result<FileOutcome> {
+    let target = std::path::absolute(path)?;
+    let entry = recovery::latest_for(&target)
+        .map_err(|e| io_error(&target, e))?
+        .ok_or_else(|| nothing_to_recover(&target))?;
+
+    println!(
+        "doasedit: {}: applying the edit saved {} ({})",
+        path,
+        recovery::format_age(entry.saved_at),
+        entry.reason
+    );
+    recover_file(&entry, editor, tmp_dir, helper, options)
+}
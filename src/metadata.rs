@@ -0,0 +1,206 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{fchown, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Extended attribute holding the POSIX access ACL
+const ACL_XATTR: &str = "system.posix_acl_access";
+
+/// Extended attribute holding the SELinux context
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// Everything about a file that write-back has to carry over
+#[derive(Debug, Clone)]
+pub struct FileMetadata {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+/// Read owner, mode, ACL, SELinux context and preserved xattrs of a file
+pub fn read_metadata(path: &Path) -> io::Result<FileMetadata> {
+    let metadata = fs::metadata(path)?;
+
+    let mut xattrs = Vec::new();
+    for name in list_xattrs(path)? {
+        if !is_preserved_xattr(&name) {
+            continue;
+        }
+        // The attribute may vanish between listing and reading it
+        if let Some(value) = get_xattr(path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+
+    Ok(FileMetadata {
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mode: metadata.mode() & 0o7777,
+        xattrs,
+    })
+}
+
+/// Apply metadata to an open file, returning one message per xattr that failed
+///
+/// Ownership goes first since `chown` clears set-id bits and capabilities,
+/// then the mode, then the xattrs so an ACL is not clobbered by the `chmod`.
+/// Owner, group and mode must be carried over, or a root-owned file could be
+/// replaced by one its editor owns, so failing to set them is an error. ACLs,
+/// SELinux contexts and other xattrs are reported and skipped.
+pub fn apply_metadata(file: &File, metadata: &FileMetadata) -> io::Result<Vec<String>> {
+    let current = file.metadata()?;
+    if current.uid() != metadata.uid {
+        fchown(file, Some(metadata.uid), None)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot set owner: {}", e)))?;
+    }
+    if current.gid() != metadata.gid {
+        fchown(file, None, Some(metadata.gid))
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot set group: {}", e)))?;
+    }
+    file.set_permissions(Permissions::from_mode(metadata.mode))
+        .map_err(|e| io::Error::new(e.kind(), format!("cannot set mode: {}", e)))?;
+
+    let mut failures = Vec::new();
+    for (name, value) in &metadata.xattrs {
+        if let Err(e) = set_xattr(file, name, value) {
+            failures.push(format!("{}: {}", property_name(name), e));
+        }
+    }
+
+    Ok(failures)
+}
+
+/// The raw POSIX access ACL of a file, or `None` if it only has mode bits
+pub fn read_acl(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match get_xattr(path, OsStr::new(ACL_XATTR)) {
+        Err(e) if is_unsupported(&e) => Ok(None),
+        result => result,
+    }
+}
+
+/// Human readable name of the property an xattr stores
+pub fn property_name(name: &OsStr) -> String {
+    if name == ACL_XATTR {
+        "ACL".to_string()
+    } else if name == SELINUX_XATTR {
+        "SELinux context".to_string()
+    } else {
+        format!("extended attribute '{}'", name.to_string_lossy())
+    }
+}
+
+/// Only ACLs, `security.*` (SELinux, capabilities, ...) and `user.*` are copied
+fn is_preserved_xattr(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    name == ACL_XATTR.as_bytes() || name.starts_with(b"security.") || name.starts_with(b"user.")
+}
+
+fn path_cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))
+}
+
+fn name_cstring(name: &OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+fn is_unsupported(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP)
+}
+
+/// List xattr names; filesystems without xattr support have none
+fn list_xattrs(path: &Path) -> io::Result<Vec<OsString>> {
+    let c_path = path_cstring(path)?;
+
+    loop {
+        let size = unsafe { libc::listxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return if is_unsupported(&e) {
+                Ok(Vec::new())
+            } else {
+                Err(e)
+            };
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe { libc::listxattr(c_path.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            // The list grew between the two calls
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+
+        buf.truncate(size as usize);
+        return Ok(buf
+            .split(|&byte| byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsString::from_vec(name.to_vec()))
+            .collect());
+    }
+}
+
+/// Read one xattr, or `None` if it no longer exists
+fn get_xattr(path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+    let c_path = path_cstring(path)?;
+    let c_name = name_cstring(name)?;
+
+    loop {
+        let size =
+            unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return if e.raw_os_error() == Some(libc::ENODATA) {
+                Ok(None)
+            } else {
+                Err(e)
+            };
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::getxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(e);
+        }
+
+        buf.truncate(size as usize);
+        return Ok(Some(buf));
+    }
+}
+
+fn set_xattr(file: &File, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let c_name = name_cstring(name)?;
+    let result = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,651 @@
+use crate::backup::{BackupMode, BackupPolicy};
+use crate::error::{helper_protocol_error, Result};
+use crate::resolve::{Identity, SymlinkPolicy};
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Largest frame either side will accept
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+/// A request sent from doasedit to the privileged helper
+#[derive(Debug)]
+pub enum Request {
+    /// Resolve a target once and keep its directory open under a handle
+    Open {
+        path: PathBuf,
+        symlinks: SymlinkPolicy,
+    },
+    /// Status of an opened target
+    Stat {
+        handle: u32,
+    },
+    Read {
+        handle: u32,
+    },
+    Write {
+        handle: u32,
+        data: Vec<u8>,
+        backup: BackupPolicy,
+    },
+    Validate {
+        argv: Vec<OsString>,
+    },
+    Shutdown,
+}
+
+impl Request {
+    /// A short description of the request for error messages
+    pub fn describe(&self) -> String {
+        match self {
+            Request::Open { path, .. } => format!("open {}", path.display()),
+            Request::Stat { handle } => format!("stat handle {}", handle),
+            Request::Read { handle } => format!("read handle {}", handle),
+            Request::Write { handle, .. } => format!("write handle {}", handle),
+            Request::Validate { argv } => {
+                let words: Vec<_> = argv.iter().map(|word| word.to_string_lossy()).collect();
+                format!("run {}", words.join(" "))
+            }
+            Request::Shutdown => "shutdown".to_string(),
+        }
+    }
+}
+
+/// File metadata as seen by the helper
+#[derive(Debug, Clone)]
+pub struct StatInfo {
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    /// Extra properties write-back will carry over (ACL, SELinux context, xattrs)
+    pub preserved: Vec<String>,
+}
+
+/// A reply from the privileged helper
+#[derive(Debug)]
+pub enum Response {
+    Ready {
+        euid: u32,
+    },
+    /// Status of an opened target, `None` if it does not exist (yet)
+    Stat(Option<StatInfo>),
+    Opened {
+        handle: u32,
+        identity: Identity,
+    },
+    Data(Vec<u8>),
+    /// The opened target does not exist (yet), or its directory does not
+    /// exist when opening it
+    NotFound,
+    Done,
+    Written {
+        backup: Option<PathBuf>,
+        failures: Vec<String>,
+    },
+    Validated {
+        success: bool,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    Error(String),
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        match self {
+            Request::Stat { handle } => {
+                enc.u8(1);
+                enc.u32(*handle);
+            }
+            Request::Read { handle } => {
+                enc.u8(2);
+                enc.u32(*handle);
+            }
+            Request::Write {
+                handle,
+                data,
+                backup,
+            } => {
+                enc.u8(3);
+                enc.u32(*handle);
+                enc.bytes(data);
+                enc.u8(backup.mode.as_u8());
+                enc.bytes(backup.dir.as_os_str().as_bytes());
+                enc.u32(backup.keep);
+                enc.u32(backup.max_age_days);
+            }
+            Request::Validate { argv } => {
+                enc.u8(4);
+                enc.u32(argv.len() as u32);
+                for arg in argv {
+                    enc.bytes(arg.as_bytes());
+                }
+            }
+            Request::Shutdown => enc.u8(5),
+            Request::Open { path, symlinks } => {
+                enc.u8(6);
+                enc.bytes(path.as_os_str().as_bytes());
+                enc.u8(symlinks.as_u8());
+            }
+        }
+        enc.0
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Request> {
+        let mut dec = Decoder(frame);
+        let request = match dec.u8()? {
+            1 => Request::Stat { handle: dec.u32()? },
+            2 => Request::Read { handle: dec.u32()? },
+            3 => Request::Write {
+                handle: dec.u32()?,
+                data: dec.bytes()?,
+                backup: BackupPolicy {
+                    mode: BackupMode::from_u8(dec.u8()?)
+                        .ok_or_else(|| helper_protocol_error("unknown backup mode"))?,
+                    dir: dec.path()?,
+                    keep: dec.u32()?,
+                    max_age_days: dec.u32()?,
+                },
+            },
+            4 => {
+                let count = dec.u32()?;
+                let mut argv = Vec::new();
+                for _ in 0..count {
+                    argv.push(OsString::from_vec(dec.bytes()?));
+                }
+                Request::Validate { argv }
+            }
+            5 => Request::Shutdown,
+            6 => Request::Open {
+                path: dec.path()?,
+                symlinks: SymlinkPolicy::from_u8(dec.u8()?)
+                    .ok_or_else(|| helper_protocol_error("unknown symlink policy"))?,
+            },
+            tag => return Err(helper_protocol_error(&format!("unknown request {}", tag))),
+        };
+        dec.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+        match self {
+            Response::Ready { euid } => {
+                enc.u8(1);
+                enc.u32(*euid);
+            }
+            Response::Stat(None) => enc.u8(2),
+            Response::Stat(Some(info)) => {
+                enc.u8(3);
+                enc.u8(info.is_file as u8);
+                enc.u8(info.is_dir as u8);
+                enc.u32(info.uid);
+                enc.u32(info.gid);
+                enc.u32(info.mode);
+                enc.strings(&info.preserved);
+            }
+            Response::Data(data) => {
+                enc.u8(4);
+                enc.bytes(data);
+            }
+            Response::Done => enc.u8(5),
+            Response::Validated {
+                success,
+                stdout,
+                stderr,
+            } => {
+                enc.u8(6);
+                enc.u8(*success as u8);
+                enc.bytes(stdout);
+                enc.bytes(stderr);
+            }
+            Response::Error(msg) => {
+                enc.u8(7);
+                enc.bytes(msg.as_bytes());
+            }
+            Response::Written { backup, failures } => {
+                enc.u8(8);
+                let backup = backup.as_deref().unwrap_or(Path::new(""));
+                enc.bytes(backup.as_os_str().as_bytes());
+                enc.strings(failures);
+            }
+            Response::Opened { handle, identity } => {
+                enc.u8(9);
+                enc.u32(*handle);
+                enc.u64(identity.dir.0);
+                enc.u64(identity.dir.1);
+                match identity.entry {
+                    Some((dev, ino)) => {
+                        enc.u8(1);
+                        enc.u64(dev);
+                        enc.u64(ino);
+                    }
+                    None => enc.u8(0),
+                }
+            }
+            Response::NotFound => enc.u8(10),
+        }
+        enc.0
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Response> {
+        let mut dec = Decoder(frame);
+        let response = match dec.u8()? {
+            1 => Response::Ready { euid: dec.u32()? },
+            2 => Response::Stat(None),
+            3 => Response::Stat(Some(StatInfo {
+                is_file: dec.u8()? != 0,
+                is_dir: dec.u8()? != 0,
+                uid: dec.u32()?,
+                gid: dec.u32()?,
+                mode: dec.u32()?,
+                preserved: dec.strings()?,
+            })),
+            4 => Response::Data(dec.bytes()?),
+            5 => Response::Done,
+            6 => Response::Validated {
+                success: dec.u8()? != 0,
+                stdout: dec.bytes()?,
+                stderr: dec.bytes()?,
+            },
+            7 => Response::Error(dec.string()?),
+            8 => Response::Written {
+                backup: Some(dec.path()?).filter(|path| !path.as_os_str().is_empty()),
+                failures: dec.strings()?,
+            },
+            9 => Response::Opened {
+                handle: dec.u32()?,
+                identity: Identity {
+                    dir: (dec.u64()?, dec.u64()?),
+                    entry: match dec.u8()? {
+                        0 => None,
+                        _ => Some((dec.u64()?, dec.u64()?)),
+                    },
+                },
+            },
+            10 => Response::NotFound,
+            tag => return Err(helper_protocol_error(&format!("unknown response {}", tag))),
+        };
+        dec.finish()?;
+        Ok(response)
+    }
+}
+
+/// Write one length-prefixed frame
+///
+/// A payload the other side would refuse as too large is not sent.
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|&len| len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes is too large for a helper frame", payload.len()),
+            )
+        })?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Read one length-prefixed frame, or `None` at end of stream
+///
+/// The stream may only end between frames; a frame cut off anywhere,
+/// including inside its length, is an error.
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < len_buf.len() {
+        match reader.read(&mut len_buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(helper_protocol_error("truncated frame")),
+            Ok(count) => filled += count,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let len = u32::from_be_bytes(len_buf);
+    if len > MAX_FRAME_LEN {
+        return Err(helper_protocol_error("frame too large"));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    fn strings(&mut self, values: &[String]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.bytes(value.as_bytes());
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(helper_protocol_error("truncated frame"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let count = self.u32()?;
+        let mut values = Vec::new();
+        for _ in 0..count {
+            values.push(self.string()?);
+        }
+        Ok(values)
+    }
+
+    fn path(&mut self) -> Result<PathBuf> {
+        Ok(PathBuf::from(OsString::from_vec(self.bytes()?)))
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(helper_protocol_error("trailing bytes in frame"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DoaseditError;
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::Stat { handle: 1 },
+            Request::Open {
+                path: PathBuf::from(OsString::from_vec(b"/etc/\xff name".to_vec())),
+                symlinks: SymlinkPolicy::ReplaceLink,
+            },
+            Request::Open {
+                path: PathBuf::new(),
+                symlinks: SymlinkPolicy::Refuse,
+            },
+            Request::Read { handle: u32::MAX },
+            Request::Write {
+                handle: 7,
+                data: b"permit :wheel\n\0".to_vec(),
+                backup: BackupPolicy {
+                    mode: BackupMode::Directory,
+                    dir: PathBuf::from("/var/backups/doasedit"),
+                    keep: 5,
+                    max_age_days: 30,
+                },
+            },
+            Request::Write {
+                handle: 0,
+                data: Vec::new(),
+                backup: BackupPolicy::default(),
+            },
+            Request::Validate {
+                argv: vec![
+                    OsString::from("visudo"),
+                    OsString::from("-cf"),
+                    OsString::from_vec(b"/tmp/\x80".to_vec()),
+                ],
+            },
+            Request::Validate { argv: Vec::new() },
+            Request::Shutdown,
+        ]
+    }
+
+    fn responses() -> Vec<Response> {
+        vec![
+            Response::Ready { euid: 0 },
+            Response::Stat(None),
+            Response::Stat(Some(StatInfo {
+                is_file: true,
+                is_dir: false,
+                uid: 0,
+                gid: 10,
+                mode: 0o100440,
+                preserved: vec!["ACL".to_string(), "SELinux context".to_string()],
+            })),
+            Response::Stat(Some(StatInfo {
+                is_file: false,
+                is_dir: true,
+                uid: u32::MAX,
+                gid: 0,
+                mode: 0o40755,
+                preserved: Vec::new(),
+            })),
+            Response::Opened {
+                handle: 3,
+                identity: Identity {
+                    dir: (u64::MAX, 2),
+                    entry: Some((64769, 1 << 40)),
+                },
+            },
+            Response::Opened {
+                handle: 4,
+                identity: Identity {
+                    dir: (1, 2),
+                    entry: None,
+                },
+            },
+            Response::Data(vec![0, 1, 2, 255]),
+            Response::Data(Vec::new()),
+            Response::NotFound,
+            Response::Done,
+            Response::Written {
+                backup: Some(PathBuf::from("/etc/doas.conf.~1~")),
+                failures: vec!["cannot copy security.selinux: denied".to_string()],
+            },
+            Response::Written {
+                backup: None,
+                failures: Vec::new(),
+            },
+            Response::Validated {
+                success: false,
+                stdout: b"line 3: syntax error\n".to_vec(),
+                stderr: Vec::new(),
+            },
+            Response::Validated {
+                success: true,
+                stdout: Vec::new(),
+                stderr: b"ok".to_vec(),
+            },
+            Response::Error("permission denied".to_string()),
+        ]
+    }
+
+    fn assert_protocol_error<T: std::fmt::Debug>(result: Result<T>, message: &str) {
+        match result {
+            Err(DoaseditError::HelperProtocol { message: found }) => {
+                assert_eq!(found, message)
+            }
+            other => panic!("expected {:?}, got {:?}", message, other),
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let frame = request.encode();
+            let decoded = Request::decode(&frame).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", request));
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in responses() {
+            let frame = response.encode();
+            let decoded = Response::decode(&frame).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", response));
+        }
+    }
+
+    #[test]
+    fn truncated_and_padded_payloads_are_rejected() {
+        for frame in requests().iter().map(Request::encode) {
+            for len in 0..frame.len() {
+                assert_protocol_error(Request::decode(&frame[..len]), "truncated frame");
+            }
+            let padded = [frame.as_slice(), &[0]].concat();
+            assert_protocol_error(Request::decode(&padded), "trailing bytes in frame");
+        }
+        for frame in responses().iter().map(Response::encode) {
+            for len in 0..frame.len() {
+                assert_protocol_error(Response::decode(&frame[..len]), "truncated frame");
+            }
+            let padded = [frame.as_slice(), &[0]].concat();
+            assert_protocol_error(Response::decode(&padded), "trailing bytes in frame");
+        }
+    }
+
+    #[test]
+    fn oversized_inner_lengths_are_rejected() {
+        // A data length or argument count far beyond the frame must fail
+        // cleanly rather than allocate or panic
+        let mut frame = vec![3];
+        frame.extend_from_slice(&7u32.to_be_bytes());
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_protocol_error(Request::decode(&frame), "truncated frame");
+
+        let mut frame = vec![4];
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_protocol_error(Request::decode(&frame), "truncated frame");
+
+        let mut frame = vec![3, 1, 0];
+        frame.extend_from_slice(&[0; 12]);
+        frame.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert_protocol_error(Response::decode(&frame), "truncated frame");
+    }
+
+    #[test]
+    fn unknown_tags_are_rejected() {
+        for tag in [0, 7, 255] {
+            assert_protocol_error(Request::decode(&[tag]), &format!("unknown request {}", tag));
+        }
+        for tag in [0, 11, 255] {
+            assert_protocol_error(
+                Response::decode(&[tag]),
+                &format!("unknown response {}", tag),
+            );
+        }
+
+        let mut frame = Request::Open {
+            path: PathBuf::from("/etc/hosts"),
+            symlinks: SymlinkPolicy::Follow,
+        }
+        .encode();
+        *frame.last_mut().unwrap() = 9;
+        assert_protocol_error(Request::decode(&frame), "unknown symlink policy");
+
+        let mut frame = vec![3];
+        frame.extend_from_slice(&1u32.to_be_bytes());
+        frame.extend_from_slice(&0u32.to_be_bytes());
+        frame.push(4);
+        assert_protocol_error(Request::decode(&frame), "unknown backup mode");
+    }
+
+    #[test]
+    fn frames_round_trip_through_a_stream() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"first").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+        write_frame(&mut stream, &Request::Shutdown.encode()).unwrap();
+
+        let mut reader = stream.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        let frame = read_frame(&mut reader).unwrap().unwrap();
+        assert!(matches!(
+            Request::decode(&frame).unwrap(),
+            Request::Shutdown
+        ));
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn cut_off_and_oversized_frames_are_rejected() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"payload").unwrap();
+        for len in 1..stream.len() {
+            let mut reader = &stream[..len];
+            let result = read_frame(&mut reader);
+            assert!(result.is_err(), "{} bytes read as {:?}", len, result);
+        }
+
+        let oversized = (MAX_FRAME_LEN + 1).to_be_bytes();
+        assert_protocol_error(read_frame(&mut &oversized[..]), "frame too large");
+        let largest = u32::MAX.to_be_bytes();
+        assert_protocol_error(read_frame(&mut &largest[..]), "frame too large");
+
+        let mut stream = Vec::new();
+        let payload = vec![0; MAX_FRAME_LEN as usize + 1];
+        match write_frame(&mut stream, &payload) {
+            Err(DoaseditError::Io { source, .. }) => {
+                assert_eq!(source.kind(), io::ErrorKind::InvalidInput)
+            }
+            other => panic!("oversized frame written: {:?}", other),
+        }
+        assert!(stream.is_empty());
+    }
+}
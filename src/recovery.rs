@@ -0,0 +1,188 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Files making up one saved edit
+const TARGET_FILE: &str = "target";
+const ORIGINAL_FILE: &str = "original";
+const EDITED_FILE: &str = "edited";
+const REASON_FILE: &str = "reason";
+
+/// An edit that could not be written, kept so it can be applied later
+#[derive(Debug)]
+pub struct Entry {
+    pub dir: PathBuf,
+    /// The file the edit was meant for
+    pub target: PathBuf,
+    /// Seconds since the epoch when the edit was saved
+    pub saved_at: u64,
+    /// Why the edit was not written
+    pub reason: String,
+}
+
+impl Entry {
+    /// The content of the target when editing started
+    pub fn original_path(&self) -> PathBuf {
+        self.dir.join(ORIGINAL_FILE)
+    }
+
+    /// The edited content
+    pub fn edited_path(&self) -> PathBuf {
+        self.dir.join(EDITED_FILE)
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.dir)
+    }
+}
+
+/// The per-user recovery store, `$XDG_STATE_HOME/doasedit/recovery`
+///
+/// `XDG_STATE_HOME` defaults to `~/.local/state`; a relative value is ignored
+/// as the XDG specification requires.
+pub fn recovery_dir() -> Option<PathBuf> {
+    let state_home = env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| {
+            env::var_os("HOME")
+                .map(PathBuf::from)
+                .filter(|path| path.is_absolute())
+                .map(|home| home.join(".local/state"))
+        })?;
+    Some(state_home.join("doasedit").join("recovery"))
+}
+
+/// Save an edit that was not written, returning the directory it went to
+pub fn save(target: &Path, original: &Path, edited: &Path, reason: &str) -> io::Result<PathBuf> {
+    let store =
+        recovery_dir().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&store)?;
+
+    let saved_at = now();
+    let mut index = 0;
+    let dir = loop {
+        let dir = store.join(format!("{}-{}-{}", saved_at, process::id(), index));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => break dir,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => index += 1,
+            Err(e) => return Err(e),
+        }
+    };
+
+    write_private(&dir.join(TARGET_FILE), target.as_os_str().as_bytes())?;
+    // A file that did not exist yet has no original
+    let original = fs::read(original).unwrap_or_default();
+    write_private(&dir.join(ORIGINAL_FILE), &original)?;
+    write_private(&dir.join(EDITED_FILE), &fs::read(edited)?)?;
+    write_private(&dir.join(REASON_FILE), reason.as_bytes())?;
+
+    Ok(dir)
+}
+
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Every saved edit, oldest first
+pub fn list() -> io::Result<Vec<Entry>> {
+    let store = match recovery_dir() {
+        Some(store) => store,
+        None => return Ok(Vec::new()),
+    };
+    let entries = match fs::read_dir(&store) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut saved = Vec::new();
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        let saved_at = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.split('-').next())
+            .and_then(|secs| secs.parse().ok());
+        let target = fs::read(dir.join(TARGET_FILE)).ok();
+
+        // Skip anything that is not a complete entry
+        if let (Some(saved_at), Some(target)) = (saved_at, target) {
+            if !dir.join(EDITED_FILE).is_file() {
+                continue;
+            }
+            let reason = fs::read_to_string(dir.join(REASON_FILE)).unwrap_or_default();
+            saved.push(Entry {
+                dir,
+                target: PathBuf::from(OsString::from_vec(target)),
+                saved_at,
+                reason,
+            });
+        }
+    }
+
+    saved.sort_by(|a, b| (a.saved_at, &a.dir).cmp(&(b.saved_at, &b.dir)));
+    Ok(saved)
+}
+
+/// The newest saved edit of `target`
+pub fn latest_for(target: &Path) -> io::Result<Option<Entry>> {
+    Ok(list()?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.target == target))
+}
+
+/// Print one line per saved edit: target, age and why it was not written
+pub fn print_entries(entries: &[Entry]) {
+    let width = entries
+        .iter()
+        .map(|entry| entry.target.as_os_str().len())
+        .max()
+        .unwrap_or(0);
+
+    for entry in entries {
+        println!(
+            "  {:<width$}  {:<14}  {}",
+            entry.target.display(),
+            format_age(entry.saved_at),
+            entry.reason,
+            width = width
+        );
+    }
+}
+
+/// How long ago a timestamp was, e.g. "5 minutes ago"
+pub fn format_age(saved_at: u64) -> String {
+    let secs = now().saturating_sub(saved_at);
+    let (count, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {}{} ago", count, unit, plural)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,345 @@
+use crate::access::user_can;
+use crate::metadata::{read_metadata, FileMetadata};
+use nix::errno::Errno;
+use nix::fcntl::{openat, readlinkat, AtFlags, OFlag, AT_FDCWD};
+use nix::sys::stat::{fstat, fstatat, FileStat, Mode, SFlag};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::{Component, Path, PathBuf};
+
+/// Most symbolic links followed while resolving one path, as in the kernel
+const MAX_SYMLINKS: u32 = 40;
+
+/// What to do when the file named on the command line is a symbolic link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Refuse to edit it
+    Refuse,
+    /// Edit and write back the file the link points to
+    #[default]
+    Follow,
+    /// Edit the content the link points to, but write it back as a regular
+    /// file in place of the link
+    ReplaceLink,
+}
+
+impl SymlinkPolicy {
+    pub fn parse(name: &str) -> Option<SymlinkPolicy> {
+        match name {
+            "refuse" => Some(SymlinkPolicy::Refuse),
+            "follow" => Some(SymlinkPolicy::Follow),
+            "replace-link" => Some(SymlinkPolicy::ReplaceLink),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SymlinkPolicy::Refuse => "refuse",
+            SymlinkPolicy::Follow => "follow",
+            SymlinkPolicy::ReplaceLink => "replace-link",
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            SymlinkPolicy::Refuse => 0,
+            SymlinkPolicy::Follow => 1,
+            SymlinkPolicy::ReplaceLink => 2,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<SymlinkPolicy> {
+        match value {
+            0 => Some(SymlinkPolicy::Refuse),
+            1 => Some(SymlinkPolicy::Follow),
+            2 => Some(SymlinkPolicy::ReplaceLink),
+            _ => None,
+        }
+    }
+}
+
+/// Device and inode of a target's directory and of the entry in it
+///
+/// Two resolutions of the same path agree on these exactly when they lead to
+/// the same place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub dir: (u64, u64),
+    /// `None` while the file does not exist
+    pub entry: Option<(u64, u64)>,
+}
+
+/// A file resolved once, with its directory held open
+///
+/// Every later read and write goes through the directory descriptor, so
+/// swapping a directory or link in the path afterwards cannot redirect them.
+#[derive(Debug)]
+pub struct Target {
+    dir: File,
+    name: OsString,
+    /// The path the target was found at, with links resolved
+    path: PathBuf,
+    /// The file a link to be replaced points to; content and metadata come
+    /// from it
+    source: Option<Box<Target>>,
+}
+
+/// Resolve `path` one component at a time without letting the kernel follow
+/// any symbolic link
+///
+/// Links in directory components are read and resolved here, also one
+/// component at a time. A link in the last component is handled according
+/// to `symlinks`. The file itself need not exist, but its directory must.
+pub fn resolve(path: &Path, symlinks: SymlinkPolicy) -> io::Result<Target> {
+    let path = std::path::absolute(path)?;
+    let mut pending = components(&path);
+    let mut dir = open_dir(AT_FDCWD, Path::new("/"))?;
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            dir = open_dir(&dir, Path::new(".."))?;
+            resolved.pop();
+            continue;
+        }
+
+        let last = pending.is_empty();
+        let stat = match fstatat(&dir, name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(stat) => Some(stat),
+            Err(Errno::ENOENT) if last => None,
+            Err(e) => return Err(component_error(&resolved.join(&name), e)),
+        };
+
+        if stat.as_ref().is_some_and(is_symlink) {
+            let link_path = resolved.join(&name);
+            let link = PathBuf::from(
+                readlinkat(&dir, name.as_os_str()).map_err(|e| component_error(&link_path, e))?,
+            );
+
+            if last && symlinks == SymlinkPolicy::Refuse {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("symbolic link to {}; see --symlinks", link.display()),
+                ));
+            }
+            if last && symlinks == SymlinkPolicy::ReplaceLink {
+                let source = resolve(&resolved.join(&link), SymlinkPolicy::Follow)?;
+                return Ok(Target {
+                    dir,
+                    name,
+                    path: link_path,
+                    source: Some(Box::new(source)),
+                });
+            }
+
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(component_error(&link_path, Errno::ELOOP));
+            }
+            if link.is_absolute() {
+                dir = open_dir(AT_FDCWD, Path::new("/"))?;
+                resolved = PathBuf::from("/");
+            }
+            for component in components(&link).into_iter().rev() {
+                pending.push_front(component);
+            }
+            continue;
+        }
+
+        if last {
+            let path = resolved.join(&name);
+            return Ok(Target {
+                dir,
+                name,
+                path,
+                source: None,
+            });
+        }
+
+        dir = open_dir(&dir, Path::new(&name)).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{}: {}", resolved.join(&name).display(), e),
+            )
+        })?;
+        resolved.push(&name);
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} does not name a file", path.display()),
+    ))
+}
+
+impl Target {
+    pub fn dir(&self) -> &File {
+        &self.dir
+    }
+
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Open the current content for reading, or `None` if there is none yet
+    pub fn open(&self) -> io::Result<Option<File>> {
+        if let Some(source) = &self.source {
+            return source.open();
+        }
+
+        // Non-blocking so a FIFO swapped in cannot hang the open
+        let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC | OFlag::O_NONBLOCK;
+        match openat(&self.dir, self.name.as_os_str(), flags, Mode::empty()) {
+            Ok(fd) => {
+                let file = File::from(fd);
+                self.check_regular(&fstat(&file)?)?;
+                Ok(Some(file))
+            }
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(component_error(&self.path, e)),
+        }
+    }
+
+    /// The current content, or `None` if the file does not exist
+    pub fn read(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.open()? {
+            Some(mut file) => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)?;
+                Ok(Some(content))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether writing replaces a link rather than the file it points to
+    pub fn replaces_link(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// Metadata write-back should carry over, or `None` for a new file
+    ///
+    /// This needs no read permission, so it also works for write-only files.
+    pub fn metadata(&self) -> io::Result<Option<FileMetadata>> {
+        if let Some(source) = &self.source {
+            return source.metadata();
+        }
+
+        let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = match openat(&self.dir, self.name.as_os_str(), flags, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) => return Ok(None),
+            Err(e) => return Err(component_error(&self.path, e)),
+        };
+        self.check_regular(&fstat(&fd)?)?;
+        // The descriptor's /proc entry reaches exactly the file that was opened
+        read_metadata(&fd_path(&fd)).map(Some)
+    }
+
+    /// Status of the file the content comes from, or `None` if it does not
+    /// exist
+    ///
+    /// Links are not followed, so one swapped in shows up as a link.
+    pub fn stat(&self) -> io::Result<Option<FileStat>> {
+        if let Some(source) = &self.source {
+            return source.stat();
+        }
+
+        match fstatat(
+            &self.dir,
+            self.name.as_os_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ) {
+            Ok(stat) => Ok(Some(stat)),
+            Err(Errno::ENOENT) => Ok(None),
+            Err(e) => Err(component_error(&self.path, e)),
+        }
+    }
+
+    /// Whether the current user may access the existing file with all of
+    /// `bits`, see [`user_can`]
+    pub fn user_can(&self, bits: u32) -> io::Result<bool> {
+        if let Some(source) = &self.source {
+            return source.user_can(bits);
+        }
+
+        let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = openat(&self.dir, self.name.as_os_str(), flags, Mode::empty())
+            .map_err(|e| component_error(&self.path, e))?;
+        self.check_regular(&fstat(&fd)?)?;
+        user_can(&fd_path(&fd), bits)
+    }
+
+    /// Where the target is now, to compare with another resolution of it
+    pub fn identity(&self) -> io::Result<Identity> {
+        let dir = fstat(&self.dir)?;
+        let entry = match fstatat(
+            &self.dir,
+            self.name.as_os_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ) {
+            Ok(stat) => Some((stat.st_dev, stat.st_ino)),
+            Err(Errno::ENOENT) => None,
+            Err(e) => return Err(component_error(&self.path, e)),
+        };
+        Ok(Identity {
+            dir: (dir.st_dev, dir.st_ino),
+            entry,
+        })
+    }
+
+    fn check_regular(&self, stat: &FileStat) -> io::Result<()> {
+        if is_symlink(stat) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} was replaced by a symbolic link", self.path.display()),
+            ));
+        }
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFREG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file", self.path.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A path that reaches exactly the file or directory behind `fd`
+pub fn fd_path(fd: &impl AsRawFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Open a directory relative to `dir`, refusing to follow a link
+pub fn open_dir(dir: impl AsFd, path: &Path) -> io::Result<File> {
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    Ok(File::from(openat(dir, path, flags, Mode::empty())?))
+}
+
+/// The names to walk for a path; `.` is dropped and the root is implied
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+fn is_symlink(stat: &FileStat) -> bool {
+    SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK
+}
+
+fn component_error(path: &Path, errno: Errno) -> io::Error {
+    let e = io::Error::from(errno);
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
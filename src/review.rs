@@ -0,0 +1,85 @@
+use crate::diff::unified_diff;
+use crate::error::Result;
+use crate::signals::Foreground;
+use crate::utils::read_user_input;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// What to do with the edited file after reviewing the diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewChoice {
+    Write,
+    EditAgain,
+    Discard,
+}
+
+/// Show the diff between the original and edited content and ask what to do
+pub fn review_changes(original: &Path, edited: &Path, label: &str) -> Result<ReviewChoice> {
+    let old_text = String::from_utf8_lossy(&fs::read(original)?).into_owned();
+    let new_text = String::from_utf8_lossy(&fs::read(edited)?).into_owned();
+
+    let diff = unified_diff(
+        &format!("{} (original)", label),
+        &format!("{} (edited)", label),
+        &old_text,
+        &new_text,
+        use_color(),
+    );
+    show_in_pager(&diff)?;
+
+    loop {
+        let input = read_user_input("(W)rite, (E)dit again, (D)iscard: [W/e/d]? ")?;
+
+        match input.trim().to_lowercase().as_str() {
+            "" | "w" => return Ok(ReviewChoice::Write),
+            "e" => return Ok(ReviewChoice::EditAgain),
+            "d" => return Ok(ReviewChoice::Discard),
+            _ => continue,
+        }
+    }
+}
+
+/// Colour only goes to a terminal, and never when `NO_COLOR` is set
+pub fn use_color() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && io::stdout().is_terminal()
+}
+
+/// Page text through `$PAGER` when writing to a terminal, else print it
+pub fn show_in_pager(text: &str) -> Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty());
+
+    let pager = match pager {
+        Some(pager) if io::stdout().is_terminal() => pager,
+        _ => {
+            print!("{}", text);
+            io::stdout().flush()?;
+            return Ok(());
+        }
+    };
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&pager).stdin(Stdio::piped());
+    // Let less pass colours through and quit on short output, as git does
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "FRX");
+    }
+
+    let mut child = command.spawn()?;
+    let _foreground = Foreground::track(&child);
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may exit before reading everything
+        match stdin.write_all(text.as_bytes()) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            result => result?,
+        }
+    }
+    child.wait()?;
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use crate::cleanup::wipe_active_dirs;
+use crate::error::{interrupted_by, Result};
+use nix::sys::signal::{kill, signal, SigHandler, SigSet, Signal};
+use nix::unistd::Pid;
+use std::io;
+use std::process::{self, Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// The first SIGINT, SIGTERM or SIGHUP received, 0 if none
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+/// The editor or pager currently in the foreground, 0 if none
+static FOREGROUND: AtomicI32 = AtomicI32::new(0);
+
+/// Number of write-backs in progress; signals wait for them to finish
+static CRITICAL: AtomicUsize = AtomicUsize::new(0);
+
+/// How long the main thread gets to notice a signal before the process exits
+/// regardless, e.g. while it waits for a slow validator
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Take over SIGINT, SIGTERM and SIGHUP
+///
+/// The signals are blocked in every thread and collected by a dedicated one,
+/// so nothing runs inside a signal handler. This must be called before any
+/// other thread is started; children get a clean signal mask from
+/// `std::process::Command`.
+///
+/// - While the editor or pager runs, SIGTERM and SIGHUP are forwarded to it.
+///   SIGINT already reaches it from the terminal and is left for it to handle,
+///   since editors use Ctrl-C themselves.
+/// - While a file is being written back, the signal waits until it is done,
+///   so the target is never left half-replaced.
+/// - Otherwise the main thread sees the signal at its next prompt or check and
+///   fails with an interrupted error, which saves the edit for `--recover`.
+///
+/// Either way the process ends with status 128 + the signal number, after the
+/// temporary files are wiped.
+pub fn handle_signals() -> nix::Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals.thread_block()?;
+
+    thread::spawn(move || {
+        while let Ok(received) = signals.wait() {
+            let child = FOREGROUND.load(Ordering::SeqCst);
+            if child > 0 {
+                if received == Signal::SIGINT {
+                    continue;
+                }
+                let _ = kill(Pid::from_raw(child), received);
+            }
+
+            let first = PENDING
+                .compare_exchange(0, received as i32, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok();
+            if !first && CRITICAL.load(Ordering::SeqCst) == 0 {
+                // A second signal means the user does not want to wait
+                exit_now(received);
+            }
+            if child == 0 {
+                thread::spawn(move || {
+                    thread::sleep(GRACE_PERIOD);
+                    if CRITICAL.load(Ordering::SeqCst) == 0
+                        && FOREGROUND.load(Ordering::SeqCst) == 0
+                    {
+                        exit_now(received);
+                    }
+                });
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn exit_now(received: Signal) -> ! {
+    wipe_active_dirs();
+    process::exit(128 + received as i32);
+}
+
+/// The signal number received so far, if any
+pub fn pending_signal() -> Option<i32> {
+    match PENDING.load(Ordering::SeqCst) {
+        0 => None,
+        signo => Some(signo),
+    }
+}
+
+/// Fail with an interrupted error if a signal has been received
+pub fn check_interrupted() -> Result<()> {
+    match pending_signal() {
+        Some(signo) => Err(interrupted_by(signo)),
+        None => Ok(()),
+    }
+}
+
+/// Run an interactive child such as the editor, forwarding signals to it
+pub fn run_foreground(command: &mut Command) -> io::Result<ExitStatus> {
+    let mut child = command.spawn()?;
+    let _foreground = Foreground::track(&child);
+    child.wait()
+}
+
+/// Marks a child as the foreground one until dropped
+pub struct Foreground(());
+
+impl Foreground {
+    pub fn track(child: &Child) -> Foreground {
+        FOREGROUND.store(child.id() as i32, Ordering::SeqCst);
+        Foreground(())
+    }
+}
+
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        FOREGROUND.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Holds off signals until dropped
+pub struct CriticalSection(());
+
+impl CriticalSection {
+    pub fn enter() -> CriticalSection {
+        CRITICAL.fetch_add(1, Ordering::SeqCst);
+        CriticalSection(())
+    }
+}
+
+impl Drop for CriticalSection {
+    fn drop(&mut self) {
+        CRITICAL.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The privileged helper is driven by its pipe and ends when that closes
+///
+/// Terminal signals reach it as well, since it shares the terminal, but the
+/// client decides what to do about them. A helper killed by Ctrl-C would leave
+/// the client unable to write anything.
+pub fn ignore_terminal_signals() {
+    // SAFETY: SIG_IGN installs no handler code
+    unsafe {
+        let _ = signal(Signal::SIGINT, SigHandler::SigIgn);
+        let _ = signal(Signal::SIGHUP, SigHandler::SigIgn);
+    }
+}
@@ -0,0 +1,603 @@
+use crate::lockout::Caller;
+use crate::utils::glob_match;
+use std::collections::HashMap;
+
+/// The main sudoers file, which includes the rest of the policy
+pub const SUDOERS: &str = "/etc/sudoers";
+
+/// An `@include`/`#include` or `@includedir`/`#includedir` line
+#[derive(Debug, PartialEq)]
+pub enum Include<'a> {
+    File(&'a str),
+    Dir(&'a str),
+}
+
+impl Include<'_> {
+    /// The directive on `line`, if it is one; the path may be quoted
+    pub fn parse(line: &str) -> Option<Include<'_>> {
+        let line = line.trim();
+        let rest = line.strip_prefix('@').or_else(|| line.strip_prefix('#'))?;
+        let (directive, path) = rest.split_once(char::is_whitespace)?;
+        let path = path.trim();
+        let path = path
+            .strip_prefix('"')
+            .and_then(|path| path.strip_suffix('"'))
+            .unwrap_or(path);
+        match directive {
+            "include" => Some(Include::File(path)),
+            "includedir" => Some(Include::Dir(path)),
+            _ => None,
+        }
+    }
+}
+
+/// Whether sudo reads `name` from an included directory: names ending in
+/// `~` or containing `.` are skipped
+pub fn is_fragment_name(name: &[u8]) -> bool {
+    !name.is_empty() && !name.ends_with(b"~") && !name.contains(&b'.')
+}
+
+/// A sudoers policy reduced to what decides who may run what as root
+///
+/// Include directives are skipped, so the files of the policy are given
+/// already put together; `Defaults` are skipped as well, and host names, IP addresses and netgroups other than the local
+/// host name are taken to match.
+pub struct Sudoers {
+    aliases: HashMap<String, Vec<String>>,
+    specs: Vec<UserSpec>,
+}
+
+/// `users hosts = commands`
+struct UserSpec {
+    users: Vec<String>,
+    hosts: Vec<String>,
+    commands: Vec<CommandSpec>,
+}
+
+/// One entry of a command list, with the runas list that applies to it
+struct CommandSpec {
+    /// Users the command may be run as; `None` means root only
+    runas: Option<Vec<String>>,
+    negated: bool,
+    command: String,
+}
+
+const ALIAS_KINDS: [&str; 5] = [
+    "User_Alias",
+    "Runas_Alias",
+    "Host_Alias",
+    "Cmnd_Alias",
+    "Cmd_Alias",
+];
+
+impl Sudoers {
+    pub fn parse(content: &str) -> Sudoers {
+        let mut sudoers = Sudoers {
+            aliases: HashMap::new(),
+            specs: Vec::new(),
+        };
+
+        for line in logical_lines(content) {
+            let first = line.split_whitespace().next().unwrap_or("");
+            if first.starts_with("Defaults") || first.starts_with('@') {
+                continue;
+            }
+            if ALIAS_KINDS.contains(&first) {
+                for definition in line[first.len()..].split(':') {
+                    if let Some((name, members)) = definition.split_once('=') {
+                        sudoers
+                            .aliases
+                            .insert(name.trim().to_string(), split_list(members));
+                    }
+                }
+                continue;
+            }
+            sudoers.specs.extend(parse_user_spec(&line));
+        }
+
+        sudoers
+    }
+
+    /// Whether the file lets `caller` run `command` as root; the last
+    /// matching entry decides
+    pub fn permits_root(&self, caller: &Caller, command: &[String]) -> bool {
+        let mut permitted = false;
+        for spec in &self.specs {
+            if !self.list_matches(&spec.users, &|item| caller_is(caller, item))
+                || !self.list_matches(&spec.hosts, &|item| host_is(caller, item))
+            {
+                continue;
+            }
+            for entry in &spec.commands {
+                let as_root = match &entry.runas {
+                    None => true,
+                    Some(runas) => {
+                        self.list_matches(runas, &|item| matches!(item, "root" | "#0" | "ALL"))
+                    }
+                };
+                if as_root && self.command_matches(&entry.command, command) {
+                    permitted = !entry.negated;
+                }
+            }
+        }
+        permitted
+    }
+
+    /// Whether `caller` may run anything at all as root
+    ///
+    /// Every command an entry names is tried, as the entry allows it to be
+    /// run, and a shell for `ALL`.
+    pub fn grants_root(&self, caller: &Caller) -> bool {
+        self.specs
+            .iter()
+            .flat_map(|spec| &spec.commands)
+            .filter(|entry| !entry.negated)
+            .flat_map(|entry| self.expand(&entry.command))
+            .any(|command| {
+                let command: Vec<String> = if command == "ALL" {
+                    vec!["/bin/sh".to_string()]
+                } else {
+                    // `""` allows the command without arguments
+                    command
+                        .split_whitespace()
+                        .filter(|word| *word != "\"\"")
+                        .map(str::to_string)
+                        .collect()
+                };
+                self.permits_root(caller, &command)
+            })
+    }
+
+    /// Evaluate a list such as `alice, %wheel, !bob`: the last matching item
+    /// decides, and aliases are expanded
+    fn list_matches(&self, list: &[String], matches: &dyn Fn(&str) -> bool) -> bool {
+        let mut result = false;
+        for item in list {
+            let (negated, item) = match item.strip_prefix('!') {
+                Some(item) => (true, item.trim()),
+                None => (false, item.as_str()),
+            };
+            let matched = match self.aliases.get(item) {
+                Some(members) => self.list_matches(members, matches),
+                None => matches(item),
+            };
+            if matched {
+                result = !negated;
+            }
+        }
+        result
+    }
+
+    /// Commands an entry stands for, with `Cmnd_Alias`es expanded
+    fn expand(&self, command: &str) -> Vec<String> {
+        match self.aliases.get(command) {
+            Some(members) => members
+                .iter()
+                .filter(|member| !member.starts_with('!'))
+                .flat_map(|member| self.expand(member))
+                .collect(),
+            None => vec![command.to_string()],
+        }
+    }
+
+    fn command_matches(&self, pattern: &str, command: &[String]) -> bool {
+        if let Some(members) = self.aliases.get(pattern) {
+            return self.list_matches(members, &|item| command_is(item, command));
+        }
+        command_is(pattern, command)
+    }
+}
+
+/// Lines with comments removed and continuation lines joined
+///
+/// `#` followed by a digit is a uid or, after `%`, a gid, not a comment.
+fn logical_lines(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+
+    for raw in content.lines() {
+        let mut line = raw;
+        let mut previous = ' ';
+        for (index, c) in raw.char_indices() {
+            let uid = raw[index + 1..].starts_with(|c: char| c.is_ascii_digit());
+            if c == '#' && !(uid && " \t,(=:!%".contains(previous)) {
+                line = &raw[..index];
+                break;
+            }
+            previous = c;
+        }
+
+        match line.strip_suffix('\\') {
+            Some(joined) if line.len() == raw.len() => {
+                current.push_str(joined);
+                current.push(' ');
+            }
+            _ => {
+                current.push_str(line);
+                if !current.trim().is_empty() {
+                    lines.push(current.clone());
+                }
+                current.clear();
+            }
+        }
+    }
+    if !current.trim().is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Split a comma-separated list, trimming each item
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Split at commas outside parentheses
+fn split_top_level(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(&list[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&list[start..]);
+    items
+}
+
+/// `users hosts = commands [: hosts = commands ...]`
+fn parse_user_spec(line: &str) -> Vec<UserSpec> {
+    // Commas may be followed by blanks, so close them up before splitting the
+    // user list from the host list
+    let compact = line.replace(", ", ",").replace(" ,", ",");
+    let Some((users, rest)) = compact.trim().split_once(char::is_whitespace) else {
+        return Vec::new();
+    };
+    let users = split_list(users);
+
+    rest.split(" : ")
+        .filter_map(|group| {
+            let (hosts, commands) = group.split_once('=')?;
+            Some(UserSpec {
+                users: users.clone(),
+                hosts: split_list(hosts),
+                commands: parse_commands(commands),
+            })
+        })
+        .collect()
+}
+
+fn parse_commands(list: &str) -> Vec<CommandSpec> {
+    let mut runas = None;
+    let mut commands = Vec::new();
+
+    for item in split_top_level(list) {
+        let mut item = item.trim();
+
+        // A runas list applies to the following entries too
+        if let Some(rest) = item.strip_prefix('(') {
+            if let Some((spec, rest)) = rest.split_once(')') {
+                let users = spec.split(':').next().unwrap_or("");
+                runas = Some(split_list(users));
+                item = rest.trim_start();
+            }
+        }
+
+        // Tags such as `NOPASSWD:`, options such as `TIMEOUT=10` and digests
+        loop {
+            if let Some((tag, rest)) = item.split_once(':') {
+                if !tag.is_empty() && tag.chars().all(is_upper) {
+                    item = rest.trim_start();
+                    continue;
+                }
+            }
+            match item.split_once(char::is_whitespace) {
+                Some((word, rest)) if is_option(word) || is_digest(word) => {
+                    item = rest.trim_start();
+                }
+                _ => break,
+            }
+        }
+
+        let (negated, command) = match item.strip_prefix('!') {
+            Some(command) => (true, command.trim()),
+            None => (false, item),
+        };
+        if !command.is_empty() {
+            commands.push(CommandSpec {
+                runas: runas.clone(),
+                negated,
+                command: command.to_string(),
+            });
+        }
+    }
+
+    commands
+}
+
+fn is_upper(c: char) -> bool {
+    c.is_ascii_uppercase() || c == '_'
+}
+
+fn is_option(word: &str) -> bool {
+    word.split_once('=')
+        .is_some_and(|(name, _)| !name.is_empty() && name.chars().all(is_upper))
+}
+
+/// `sha224:` through `sha512:` followed by the digest, hex or base64
+fn is_digest(word: &str) -> bool {
+    word.split_once(':').is_some_and(|(algorithm, _)| {
+        matches!(algorithm, "sha224" | "sha256" | "sha384" | "sha512")
+    })
+}
+
+fn caller_is(caller: &Caller, item: &str) -> bool {
+    match item {
+        "ALL" => true,
+        _ => match item.strip_prefix('%') {
+            Some(group) => match group.strip_prefix('#') {
+                Some(gid) => caller.in_group(gid),
+                None => caller.in_group(group),
+            },
+            None => caller.is_user(item.strip_prefix('#').unwrap_or(item)),
+        },
+    }
+}
+
+fn host_is(caller: &Caller, item: &str) -> bool {
+    let short = caller.host.split('.').next().unwrap_or(&caller.host);
+    let looks_like_name = item.chars().any(|c| c.is_ascii_alphabetic())
+        && !item.starts_with('+')
+        && !item.contains(['/', ':']);
+    item == "ALL" || item == caller.host || item == short || !looks_like_name
+}
+
+/// Whether `pattern`, a command from a sudoers entry, matches `command`
+///
+/// A directory matches the programs in it, no arguments match any, and `""`
+/// matches none.
+fn command_is(pattern: &str, command: &[String]) -> bool {
+    if pattern == "ALL" {
+        return true;
+    }
+    let Some((program, args)) = command.split_first() else {
+        return false;
+    };
+
+    let (path, pattern_args) = match pattern.split_once(char::is_whitespace) {
+        Some((path, args)) => (path, Some(args.trim())),
+        None => (pattern, None),
+    };
+
+    let path_matches = match path.strip_suffix('/') {
+        Some(dir) => program
+            .rsplit_once('/')
+            .is_some_and(|(parent, _)| parent == dir),
+        None => glob_match(path, program),
+    };
+    let args_match = match pattern_args {
+        None => true,
+        Some("\"\"") => args.is_empty(),
+        Some(pattern_args) => glob_match(pattern_args, &args.join(" ")),
+    };
+
+    path_matches && args_match
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> Caller {
+        Caller {
+            name: "alice".to_string(),
+            uid: 1000,
+            groups: vec![("alice".to_string(), 1000), ("wheel".to_string(), 10)],
+            host: "box.example.org".to_string(),
+        }
+    }
+
+    fn command(words: &str) -> Vec<String> {
+        words.split_whitespace().map(str::to_string).collect()
+    }
+
+    /// Whether `content` lets alice run each command as root
+    fn check(content: &str, cases: &[(&str, bool)]) {
+        let sudoers = Sudoers::parse(content);
+        for &(words, expected) in cases {
+            assert_eq!(
+                sudoers.permits_root(&alice(), &command(words)),
+                expected,
+                "{:?} running {:?}",
+                content,
+                words
+            );
+        }
+    }
+
+    #[test]
+    fn matches_users_and_groups() {
+        let cases = [
+            ("alice ALL=(ALL) ALL", true),
+            ("#1000 ALL=(ALL) ALL", true),
+            ("%wheel ALL=(ALL:ALL) ALL", true),
+            ("%#10 ALL=(ALL) ALL", true),
+            ("%alice ALL=(ALL) ALL", true),
+            ("ALL ALL=(ALL) ALL", true),
+            ("bob ALL=(ALL) ALL", false),
+            ("%sudo ALL=(ALL) ALL", false),
+            ("bob, alice ALL=(ALL) ALL", true),
+            ("ALL, !alice ALL=(ALL) ALL", false),
+            ("%wheel,!alice ALL=(ALL) ALL", false),
+            ("!alice, %wheel ALL=(ALL) ALL", true),
+            (
+                "User_Alias ADMINS = bob, %wheel\nADMINS ALL=(ALL) ALL",
+                true,
+            ),
+            ("User_Alias ADMINS = bob\nADMINS ALL=(ALL) ALL", false),
+        ];
+        for (content, expected) in cases {
+            check(content, &[("/bin/sh", expected)]);
+        }
+    }
+
+    #[test]
+    fn matches_hosts_and_runas_users() {
+        let cases = [
+            ("alice box=(ALL) ALL", true),
+            ("alice box.example.org=(ALL) ALL", true),
+            ("alice other=(ALL) ALL", false),
+            ("alice 10.0.0.0/8=(ALL) ALL", true),
+            ("alice other=(ALL) ALL : box=(ALL) ALL", true),
+            ("alice ALL=ALL", true),
+            ("alice ALL=(root) ALL", true),
+            ("alice ALL=(#0) ALL", true),
+            ("alice ALL=(bob) ALL", false),
+            ("alice ALL=(ALL, !root) ALL", false),
+            ("alice ALL=(:wheel) ALL", false),
+            ("Runas_Alias OP = root, operator\nalice ALL=(OP) ALL", true),
+            ("alice ALL=(bob) /bin/ls, (root) /bin/sh", true),
+            ("alice ALL=(root) /bin/ls, (bob) /bin/sh", false),
+        ];
+        for (content, expected) in cases {
+            check(content, &[("/bin/sh", expected)]);
+        }
+    }
+
+    #[test]
+    fn matches_commands() {
+        check(
+            "alice ALL=(ALL) /bin/ls, /usr/bin/, /bin/echo hi *, /bin/true \"\"",
+            &[
+                ("/bin/ls", true),
+                ("/bin/ls -l /root", true),
+                ("/usr/bin/id", true),
+                ("/usr/bin/sub/id", false),
+                ("/bin/echo hi there", true),
+                ("/bin/echo bye", false),
+                ("/bin/true", true),
+                ("/bin/true now", false),
+                ("/bin/sh", false),
+            ],
+        );
+        check(
+            "Cmnd_Alias SHELLS = /bin/sh, /bin/bash\nalice ALL=(ALL) ALL, !SHELLS",
+            &[("/bin/ls", true), ("/bin/sh", false), ("/bin/bash", false)],
+        );
+        check(
+            "alice ALL=(ALL) NOPASSWD: SETENV: /bin/ls, TIMEOUT=10 /bin/cat, \
+             sha256:abc= /bin/id",
+            &[("/bin/ls", true), ("/bin/cat", true), ("/bin/id", true)],
+        );
+    }
+
+    #[test]
+    fn last_matching_entry_decides() {
+        check(
+            "alice ALL=(ALL) !/bin/sh\n%wheel ALL=(ALL) ALL",
+            &[("/bin/sh", true)],
+        );
+        check(
+            "%wheel ALL=(ALL) ALL\nalice ALL=(ALL) !/bin/sh",
+            &[("/bin/sh", false), ("/bin/ls", true)],
+        );
+    }
+
+    #[test]
+    fn skips_defaults_includes_and_comments() {
+        let content = "\
+Defaults env_reset
+Defaults:alice !authenticate
+Defaults>root !set_logname
+@includedir /etc/sudoers.d
+#includedir /etc/sudoers.d
+#include /etc/sudoers.local
+# alice ALL=(ALL) ALL
+bob ALL=(ALL) ALL # alice ALL=(ALL) ALL
+";
+        check(content, &[("/bin/sh", false)]);
+        assert_eq!(Sudoers::parse(content).specs.len(), 1);
+    }
+
+    #[test]
+    fn parses_include_directives() {
+        let cases = [
+            (
+                "@includedir /etc/sudoers.d",
+                Some(Include::Dir("/etc/sudoers.d")),
+            ),
+            (
+                "#includedir /etc/sudoers.d",
+                Some(Include::Dir("/etc/sudoers.d")),
+            ),
+            (
+                "@include sudoers.local",
+                Some(Include::File("sudoers.local")),
+            ),
+            (
+                "  #include \"/etc/sudo ers\"",
+                Some(Include::File("/etc/sudo ers")),
+            ),
+            ("# include /etc/sudoers.local", None),
+            ("#includes /etc/sudoers.local", None),
+            ("#1000 ALL=(ALL) ALL", None),
+            ("alice ALL=(ALL) ALL", None),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(Include::parse(line), expected, "{:?}", line);
+        }
+
+        assert!(is_fragment_name(b"alice"));
+        assert!(!is_fragment_name(b"alice~"));
+        assert!(!is_fragment_name(b"alice.dpkg-old"));
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        check(
+            "alice ALL=(ALL) /bin/ls, \\\n    /bin/cat",
+            &[("/bin/ls", true), ("/bin/cat", true)],
+        );
+        check(
+            "User_Alias ADMINS = bob, \\\n  alice\nADMINS ALL=(ALL) ALL",
+            &[("/bin/sh", true)],
+        );
+    }
+
+    #[test]
+    fn grants_root_tries_every_named_command() {
+        let cases = [
+            ("alice ALL=(ALL) ALL", true),
+            ("alice ALL=(ALL) /usr/bin/apt", true),
+            (
+                "Cmnd_Alias PKG = /usr/bin/apt, /usr/bin/dpkg\nalice ALL=(ALL) PKG",
+                true,
+            ),
+            ("alice ALL=(bob) ALL", false),
+            (
+                "alice ALL=(ALL) /usr/bin/apt\nalice ALL=(ALL) !/usr/bin/apt",
+                false,
+            ),
+            ("bob ALL=(ALL) ALL", false),
+            ("", false),
+        ];
+        for (content, expected) in cases {
+            assert_eq!(
+                Sudoers::parse(content).grants_root(&alice()),
+                expected,
+                "{:?}",
+                content
+            );
+        }
+    }
+}
@@ -1,90 +1,10 @@
-use crate::error::{
-    doas_cat_permission_denied, doas_unavailable, doas_validation_error, interrupted,
-    invalid_editor, Result,
-};
-use nix::unistd::getuid;
+use crate::error::{interrupted, invalid_path, no_terminal, Result};
+use crate::signals::check_interrupted;
+use std::env;
 use std::fs;
-use std::io::{self, Write};
-use std::os::linux::fs::MetadataExt;
+use std::io::{self, IsTerminal, Write};
 use std::os::unix::fs::PermissionsExt;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-
-/// Get the current user ID
-pub fn get_current_uid() -> u32 {
-    getuid().as_raw()
-}
-
-/// Check if a file is owned by the current user
-pub fn is_file_owned_by_user(file_path: &Path) -> Result<bool> {
-    let metadata = fs::metadata(file_path)?;
-    let file_uid = metadata.st_uid();
-    let current_uid = get_current_uid();
-
-    Ok(file_uid == current_uid)
-}
-
-/// Check if a directory is owned by the current user
-pub fn is_dir_owned_by_user(dir_path: &Path) -> Result<bool> {
-    let metadata = fs::metadata(dir_path)?;
-    let dir_uid = metadata.st_uid();
-    let current_uid = get_current_uid();
-
-    Ok(dir_uid == current_uid)
-}
-
-/// Check if a directory is writable by current user
-pub fn is_dir_writable_by_user(dir_path: &Path) -> Result<bool> {
-    let metadata = fs::metadata(dir_path)?;
-    let mode = metadata.permissions().mode();
-    let dir_uid = metadata.st_uid();
-    let current_uid = get_current_uid();
-
-    // Check if directory is writable by user, group, or others
-    // But also check if current user is owner
-    Ok((mode & 0o222 != 0) && (dir_uid == current_uid))
-}
-
-/// Check if a file is writable by current user
-pub fn is_file_writable_by_user(file_path: &Path) -> Result<bool> {
-    let metadata = fs::metadata(file_path)?;
-    let mode = metadata.permissions().mode();
-    let file_uid = metadata.st_uid();
-    let current_uid = getuid().as_raw();
-
-    // Check if file is writable by user, group, or others
-    // But also check if current user is the owner
-    Ok((mode & 0o222 != 0) && (file_uid == current_uid))
-}
-
-/// Get file metadata using doas if needed
-pub fn get_file_metadata_with_doas(file_path: &Path) -> Result<(u32, u32)> {
-    let output = Command::new("doas")
-        .arg("stat")
-        .arg("-c")
-        .arg("%u %a")
-        .arg(file_path)
-        .output()
-        .map_err(|_| doas_unavailable())?;
-
-    if !output.status.success() {
-        return Err(doas_cat_permission_denied());
-    }
-
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    let parts: Vec<&str> = output_str.trim().split_whitespace().collect();
-
-    if parts.len() >= 2 {
-        let uid = parts[0]
-            .parse()
-            .map_err(|_| doas_validation_error("Invalid UID format"))?;
-        let mode = u32::from_str_radix(parts[1], 8).unwrap_or(0o644);
-
-        Ok((uid, mode))
-    } else {
-        Err(doas_validation_error("Invalid stat output"))
-    }
-}
+use std::path::{Component, Path, PathBuf};
 
 /// Check if a path is a directory (ends with /)
 pub fn is_directory_path(path: &str) -> bool {
@@ -96,11 +16,39 @@ pub fn get_parent_directory(file_path: &Path) -> PathBuf {
     file_path.parent().unwrap_or(Path::new("/")).to_path_buf()
 }
 
+/// Whether the user can be asked questions, which takes a terminal on stdin
+pub fn can_prompt() -> bool {
+    io::stdin().is_terminal()
+}
+
 /// Read user input from stdin
+///
+/// Waits for input in short slices so that a signal received meanwhile ends
+/// the prompt with an interrupted error. Without a terminal nothing is asked
+/// and an error is returned, rather than taking whatever stdin holds as the
+/// answer.
 pub fn read_user_input(prompt: &str) -> Result<String> {
+    if !can_prompt() {
+        return Err(no_terminal());
+    }
     eprint!("{}", prompt);
     io::stdout().flush().map_err(|_| interrupted())?;
 
+    loop {
+        check_interrupted()?;
+        let mut stdin = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: one valid pollfd is passed
+        let ready = unsafe { libc::poll(&mut stdin, 1, 100) };
+        if ready != 0 {
+            // Input, end of file or an error: let read_line sort it out
+            break;
+        }
+    }
+
     let mut input = String::new();
     io::stdin()
         .read_line(&mut input)
@@ -117,10 +65,158 @@ pub fn files_match(file1: &Path, file2: &Path) -> Result<bool> {
     Ok(content1 == content2)
 }
 
-/// Check if a file is a doas configuration file
-pub fn is_doas_config_file(file_path: &str) -> bool {
-    file_path.starts_with("/etc/doas")
-        && (file_path == "/etc/doas.conf" || file_path.starts_with("/etc/doas.d/"))
+/// Split a command line into words the way a POSIX shell would
+///
+/// Supports single quotes, double quotes, backslash escapes and escaped
+/// newlines; no expansion is performed. Returns `None` if a quote is left
+/// open.
+pub fn split_shell_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\\' => match chars.next() {
+                // An escaped newline joins the lines
+                Some('\n') => {}
+                Some(c) => {
+                    in_word = true;
+                    word.push(c);
+                }
+                // A trailing backslash stands for itself
+                None => {
+                    in_word = true;
+                    word.push('\\');
+                }
+            },
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '\'' => break,
+                        c => word.push(c),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            c @ ('"' | '\\' | '$' | '`') => word.push(c),
+                            '\n' => {}
+                            c => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+    Some(words)
+}
+
+/// Match a path against a glob pattern
+///
+/// `*` and `?` do not match `/`, `**` matches across directories, and
+/// `[...]` / `[!...]` match a character class.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    glob_match_bytes(pattern.as_bytes(), path.as_bytes())
+}
+
+fn glob_match_bytes(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            // `**/` may also match no directories at all
+            let rest = &pattern[2..];
+            if rest.first() == Some(&b'/') && glob_match_bytes(&rest[1..], path) {
+                return true;
+            }
+            (0..=path.len()).any(|skip| glob_match_bytes(rest, &path[skip..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for skip in 0..=path.len() {
+                if glob_match_bytes(rest, &path[skip..]) {
+                    return true;
+                }
+                if path.get(skip) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => match path.first() {
+            Some(&c) if c != b'/' => glob_match_bytes(&pattern[1..], &path[1..]),
+            _ => false,
+        },
+        Some(b'[') => {
+            let Some(&c) = path.first() else {
+                return false;
+            };
+            match match_class(&pattern[1..], c) {
+                Some((true, rest)) => glob_match_bytes(rest, &path[1..]),
+                Some((false, _)) => false,
+                // No closing bracket, treat `[` literally
+                None => c == b'[' && glob_match_bytes(&pattern[1..], &path[1..]),
+            }
+        }
+        Some(&p) => path.first() == Some(&p) && glob_match_bytes(&pattern[1..], &path[1..]),
+    }
+}
+
+/// Match `c` against a class body; returns whether it matched and the rest of the pattern
+fn match_class(class: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negated, body) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    // A `]` right after the opening bracket is a literal member
+    let end = body
+        .iter()
+        .skip(1)
+        .position(|&b| b == b']')
+        .map(|pos| pos + 1)?;
+
+    let members = &body[..end];
+    let mut matched = false;
+    let mut i = 0;
+    while i < members.len() {
+        if i + 2 < members.len() && members[i + 1] == b'-' {
+            if members[i] <= c && c <= members[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if members[i] == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+
+    Some((matched != negated && c != b'/', &body[end + 1..]))
 }
 
 /// Get the filename from a path
@@ -128,10 +224,193 @@ pub fn get_filename(path: &Path) -> Result<String> {
     path.file_name()
         .and_then(|name| name.to_str())
         .map(|s| s.to_string())
-        .ok_or_else(|| invalid_editor("Invalid file path"))
+        .ok_or_else(|| invalid_path(path))
 }
 
-/// Create a safe filename for the copy
-pub fn create_copy_filename(original_name: &str) -> String {
-    format!("copy-of-{}", original_name)
+/// Place an absolute path under `root`, e.g. `/etc/hosts` as `root/etc/hosts`
+///
+/// `.` and `..` are resolved lexically so the result never leaves `root`.
+pub fn mirror_path(root: &Path, path: &Path) -> PathBuf {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::ParentDir => {
+                relative.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    root.join(relative)
+}
+
+/// Find an executable in `$PATH`
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    let path_var = env::var_os("PATH")?;
+    env::split_paths(&path_var)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// Check if a path is a regular file with an execute bit set
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Option<Vec<String>> {
+        split_shell_words(line)
+    }
+
+    fn list(items: &[&str]) -> Option<Vec<String>> {
+        Some(items.iter().map(|item| item.to_string()).collect())
+    }
+
+    #[test]
+    fn split_shell_words_handles_quoting() {
+        let cases = [
+            ("", list(&[])),
+            ("   ", list(&[])),
+            ("vim", list(&["vim"])),
+            ("  code  --wait\t-n ", list(&["code", "--wait", "-n"])),
+            ("'my editor' -f", list(&["my editor", "-f"])),
+            ("\"my editor\" -f", list(&["my editor", "-f"])),
+            ("emacs -nw ''", list(&["emacs", "-nw", ""])),
+            ("emacs -nw \"\"", list(&["emacs", "-nw", ""])),
+            ("a'b c'd", list(&["ab cd"])),
+            ("'it'\\''s'", list(&["it's"])),
+            ("'a \\ b' \"c\"", list(&["a \\ b", "c"])),
+            ("'$HOME \"x\"'", list(&["$HOME \"x\""])),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(words(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn split_shell_words_handles_escapes() {
+        let cases = [
+            ("my\\ editor", list(&["my editor"])),
+            ("\\'", list(&["'"])),
+            ("a\\\\b", list(&["a\\b"])),
+            ("\\\"", list(&["\""])),
+            ("vi\\", list(&["vi\\"])),
+            ("vi \\\n -n", list(&["vi", "-n"])),
+            ("\"a\\\"b\"", list(&["a\"b"])),
+            ("\"\\$x \\` \\\\\"", list(&["$x ` \\"])),
+            ("\"\\n\\t\"", list(&["\\n\\t"])),
+            ("\"a\\\nb\"", list(&["ab"])),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(words(line), expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn split_shell_words_rejects_open_quotes() {
+        for line in ["'", "vim 'file", "\"", "vim \"file", "\"a\\\"", "\"a\\"] {
+            assert_eq!(words(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        let cases = [
+            ("/etc/hosts", "/etc/hosts", true),
+            ("/etc/hosts", "/etc/host", false),
+            ("/etc/*", "/etc/hosts", true),
+            ("/etc/*", "/etc/", true),
+            ("/etc/*", "/etc/ssh/sshd_config", false),
+            ("/etc/*.conf", "/etc/doas.conf", true),
+            ("/etc/*.conf", "/etc/doas.conf.bak", false),
+            ("/etc/host?", "/etc/hosts", true),
+            ("/etc/host?", "/etc/host", false),
+            ("/etc?hosts", "/etc/hosts", false),
+            ("/etc/**", "/etc/ssh/sshd_config", true),
+            ("/etc/**/*.conf", "/etc/a/b/c.conf", true),
+            ("/etc/**/*.conf", "/etc/c.conf", true),
+            ("/etc/**/sshd_config", "/etc/sshd_config", true),
+            ("/etc/**/sshd_config", "/etc/ssh/sshd_config", true),
+            ("/etc/**/sshd_config", "/etc/ssh/old_sshd_config", false),
+            ("**/*.json", "x.json", true),
+            ("**/*.json", "/srv/app/x.json", true),
+            ("**", "", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(glob_match(pattern, path), expected, "{} {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn glob_match_classes() {
+        let cases = [
+            ("/dev/sd[ab]", "/dev/sda", true),
+            ("/dev/sd[ab]", "/dev/sdc", false),
+            ("/dev/sd[a-c]", "/dev/sdc", true),
+            ("/dev/sd[a-c]", "/dev/sdd", false),
+            ("/dev/sd[!a-c]", "/dev/sdd", true),
+            ("/dev/sd[!a-c]", "/dev/sda", false),
+            ("/dev/sd[^a]", "/dev/sdb", true),
+            ("x[]]", "x]", true),
+            ("x[]a]", "xa", true),
+            ("x[]a]", "xb", false),
+            ("x[!]]", "x]", false),
+            ("x[!]]", "xa", true),
+            ("x[a-]", "x-", true),
+            ("x[!a]", "x/", false),
+            ("x[/]", "x/", false),
+            // Without a closing bracket `[` is literal
+            ("x[a", "x[a", true),
+            ("x[a", "xa", false),
+            ("x[]", "x[]", true),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(glob_match(pattern, path), expected, "{} {}", pattern, path);
+        }
+    }
+
+    #[test]
+    fn match_class_returns_the_rest_of_the_pattern() {
+        assert_eq!(match_class(b"abc]rest", b'b'), Some((true, &b"rest"[..])));
+        assert_eq!(match_class(b"!abc]rest", b'b'), Some((false, &b"rest"[..])));
+        assert_eq!(match_class(b"]]", b']'), Some((true, &b""[..])));
+        assert_eq!(match_class(b"!]]x", b'a'), Some((true, &b"x"[..])));
+        assert_eq!(match_class(b"abc", b'a'), None);
+        assert_eq!(match_class(b"]", b']'), None);
+    }
+
+    #[test]
+    fn mirror_path_stays_inside_the_root() {
+        let root = Path::new("/tmp/doasedit-1000");
+        let cases = [
+            ("/etc/hosts", "/tmp/doasedit-1000/etc/hosts"),
+            ("etc/hosts", "/tmp/doasedit-1000/etc/hosts"),
+            (
+                "/etc/./ssh//sshd_config",
+                "/tmp/doasedit-1000/etc/ssh/sshd_config",
+            ),
+            ("/etc/ssh/../hosts", "/tmp/doasedit-1000/etc/hosts"),
+            ("/../../etc/shadow", "/tmp/doasedit-1000/etc/shadow"),
+            ("/etc/../../../..", "/tmp/doasedit-1000"),
+            ("/", "/tmp/doasedit-1000"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(
+                mirror_path(root, Path::new(path)),
+                PathBuf::from(expected),
+                "{}",
+                path
+            );
+        }
+    }
 }
@@ -0,0 +1,915 @@
+use crate::doas_conf::{self, Accounts, DOAS_CONF, DOAS_FRAGMENT_DIR};
+use crate::error::{io_error, Result};
+use crate::helper::Helper;
+use crate::resolve::SymlinkPolicy;
+use crate::sudoers::{is_fragment_name, Include};
+use crate::utils::glob_match;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Checks an edited file before it replaces the original
+pub trait Validator {
+    /// Short name shown in error messages
+    fn name(&self) -> &str;
+
+    /// Check `file`, the edited copy of `target`, returning `None` if it is
+    /// valid or the problem if not
+    fn validate(&self, file: &Path, target: &Path, helper: &mut Helper) -> Result<Option<Problem>>;
+
+    /// Risky constructs in a valid `file`, shown as warnings before it
+    /// replaces `target`
+    fn lint(&self, _file: &Path, _target: &Path, _helper: &mut Helper) -> Result<Vec<Problem>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Why an edited file failed validation
+#[derive(Debug)]
+pub struct Problem {
+    pub message: String,
+    /// Line the problem was found on, counting from 1, so the editor can be
+    /// reopened there
+    pub line: Option<usize>,
+}
+
+impl Problem {
+    /// A problem on a known line
+    pub fn at_line(message: String, line: usize) -> Problem {
+        Problem {
+            message,
+            line: Some(line),
+        }
+    }
+
+    /// A problem reported by a checker, positioned by the first `line N` or
+    /// `<file>:N:` in its output
+    ///
+    /// `doas -C`, `visudo` and `sshd -t` say `line N`; most other checkers
+    /// prefix their messages with the file name and line number.
+    pub fn from_output(message: String, file: &Path) -> Problem {
+        let line = find_line_number(&message, "line ", None)
+            .or_else(|| find_line_number(&message, &format!("{}:", file.display()), Some(':')));
+        Problem { message, line }
+    }
+}
+
+impl From<String> for Problem {
+    fn from(message: String) -> Problem {
+        Problem {
+            message,
+            line: None,
+        }
+    }
+}
+
+/// The number following the first `marker` in `text` that starts a word and
+/// is followed by one, and then by `end` if given
+fn find_line_number(text: &str, marker: &str, end: Option<char>) -> Option<usize> {
+    text.match_indices(marker).find_map(|(index, _)| {
+        if text[..index].ends_with(|c: char| c.is_alphanumeric() || c == '_') {
+            return None;
+        }
+        let rest = &text[index + marker.len()..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if end.is_some_and(|end| !rest[digits..].starts_with(end)) {
+            return None;
+        }
+        rest[..digits].parse().ok().filter(|&line| line > 0)
+    })
+}
+
+/// Runs an external checker as root through the privileged helper
+///
+/// Every `{}` in the command template is replaced by the path of the edited
+/// file. The file is invalid if the command exits with a non-zero status, and
+/// its stderr and stdout make up the error message.
+pub struct CommandValidator {
+    name: String,
+    template: Vec<String>,
+}
+
+impl CommandValidator {
+    pub fn new(name: &str, template: &[&str]) -> CommandValidator {
+        CommandValidator {
+            name: name.to_string(),
+            template: template.iter().map(|word| word.to_string()).collect(),
+        }
+    }
+
+    /// A validator named after the program it runs
+    pub fn from_template(template: Vec<String>) -> CommandValidator {
+        let name = template
+            .first()
+            .map(|program| program.rsplit('/').next().unwrap_or(program).to_string())
+            .unwrap_or_default();
+        CommandValidator { name, template }
+    }
+}
+
+impl Validator for CommandValidator {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn validate(
+        &self,
+        file: &Path,
+        _target: &Path,
+        helper: &mut Helper,
+    ) -> Result<Option<Problem>> {
+        let file_name = file.to_string_lossy();
+        let argv: Vec<OsString> = self
+            .template
+            .iter()
+            .map(|word| OsString::from(word.replace("{}", &file_name)))
+            .collect();
+
+        let output = helper.validate(argv)?;
+        if output.success {
+            return Ok(None);
+        }
+
+        let mut message = String::from_utf8_lossy(&output.stderr).into_owned();
+        message.push_str(&String::from_utf8_lossy(&output.stdout));
+        let message = message.trim_end();
+
+        if message.is_empty() {
+            return Ok(Some(
+                format!("'{}' reported an error", self.template.join(" ")).into(),
+            ));
+        }
+        Ok(Some(Problem::from_output(message.to_string(), file)))
+    }
+}
+
+/// Checks doas.conf with a built-in parser, whatever doas is installed
+///
+/// The edited file is checked as part of the whole configuration, the main
+/// file followed by the fragments in `/etc/doas.d`, so rules are judged in
+/// the order doas reads them. Syntax errors fail validation; rules that give
+/// away more than they probably should, never apply or name unknown users
+/// and groups are reported as warnings. Problems in the other files are
+/// reported with their file name.
+pub struct DoasConfValidator;
+
+impl Validator for DoasConfValidator {
+    fn name(&self) -> &str {
+        "doas.conf"
+    }
+
+    fn validate(&self, file: &Path, target: &Path, helper: &mut Helper) -> Result<Option<Problem>> {
+        let files = assemble_doas_config(fs::read_to_string(file)?, target, helper)?;
+        Ok(doas_conf::parse_files(&files).err().map(|e| match e.file {
+            Some(_) => e.to_string().into(),
+            None => Problem::at_line(e.to_string(), e.line),
+        }))
+    }
+
+    fn lint(&self, file: &Path, target: &Path, helper: &mut Helper) -> Result<Vec<Problem>> {
+        let files = assemble_doas_config(fs::read_to_string(file)?, target, helper)?;
+        let rules = match doas_conf::parse_files(&files) {
+            Ok(rules) => rules,
+            Err(_) => return Ok(Vec::new()),
+        };
+
+        let accounts = Accounts::read();
+        Ok(doas_conf::lint(&rules, accounts.as_ref())
+            .into_iter()
+            .map(|warning| match warning.file {
+                Some(_) => warning.to_string().into(),
+                None => Problem::at_line(warning.to_string(), warning.line),
+            })
+            .collect())
+    }
+}
+
+/// The files of the effective doas configuration in the order doas reads
+/// them, with `content` in place of `target`
+///
+/// `/etc/doas.conf` comes first, then the fragments in `/etc/doas.d` sorted
+/// by name; a new fragment takes its sorted place. `content` is given
+/// without a path. Files the user may not read are read through the helper.
+/// If `target` is not part of the configuration, `content` is all there is.
+pub fn assemble_doas_config(
+    content: String,
+    target: &Path,
+    helper: &mut Helper,
+) -> Result<Vec<(Option<PathBuf>, String)>> {
+    assemble_doas_files(
+        Path::new(DOAS_CONF),
+        Path::new(DOAS_FRAGMENT_DIR),
+        content,
+        target,
+        &mut |path| read_config_file(path, helper),
+    )
+}
+
+/// `assemble_doas_config` for the main file `main` and fragments in
+/// `fragment_dir`, reading files with `read`
+fn assemble_doas_files(
+    main: &Path,
+    fragment_dir: &Path,
+    content: String,
+    target: &Path,
+    read: &mut dyn FnMut(&Path) -> Result<Option<String>>,
+) -> Result<Vec<(Option<PathBuf>, String)>> {
+    let fragments = config_fragments(fragment_dir, target, |name| !name.starts_with(b"."))?;
+
+    let mut paths = vec![main.to_path_buf()];
+    paths.extend(fragments);
+    if !paths.iter().any(|path| path == target) {
+        return Ok(vec![(None, content)]);
+    }
+
+    let mut files = Vec::new();
+    for path in paths {
+        if path == target {
+            files.push((None, content.clone()));
+        } else if let Some(content) = read(&path)? {
+            files.push((Some(path), content));
+        }
+    }
+    Ok(files)
+}
+
+/// Most levels of includes sudo follows
+const MAX_INCLUDE_DEPTH: usize = 128;
+
+/// The sudoers policy as sudo reads it from `main`, with `content` in place
+/// of `target`
+///
+/// Every `@include` and `@includedir` is replaced by what it names, relative
+/// paths taken from the including file's directory and a directory's
+/// fragments sorted by name; a new fragment takes its sorted place. Files are
+/// read with `read`. If `target` is not part of the policy, `content` is all
+/// there is.
+pub fn assemble_sudoers(
+    main: &Path,
+    content: String,
+    target: &Path,
+    read: &mut dyn FnMut(&Path) -> Result<Option<String>>,
+) -> Result<String> {
+    let mut assembly = SudoersAssembly {
+        target,
+        content,
+        read,
+        found: false,
+        policy: String::new(),
+    };
+    assembly.add_file(main, 0)?;
+    Ok(match assembly.found {
+        true => assembly.policy,
+        false => assembly.content,
+    })
+}
+
+struct SudoersAssembly<'a> {
+    target: &'a Path,
+    content: String,
+    read: &'a mut dyn FnMut(&Path) -> Result<Option<String>>,
+    /// Whether `target` was reached
+    found: bool,
+    policy: String,
+}
+
+impl SudoersAssembly<'_> {
+    fn add_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        // Deeper nesting is where sudo gives up, and ends include loops
+        if depth > MAX_INCLUDE_DEPTH {
+            return Ok(());
+        }
+        let text = if path == self.target {
+            self.found = true;
+            self.content.clone()
+        } else {
+            match (self.read)(path)? {
+                Some(text) => text,
+                None => return Ok(()),
+            }
+        };
+
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        for line in text.lines() {
+            match Include::parse(line) {
+                Some(Include::File(file)) => self.add_file(&dir.join(file), depth + 1)?,
+                Some(Include::Dir(included)) => {
+                    let included = dir.join(included);
+                    for fragment in config_fragments(&included, self.target, is_fragment_name)? {
+                        self.add_file(&fragment, depth + 1)?;
+                    }
+                }
+                None => {
+                    self.policy.push_str(line);
+                    self.policy.push('\n');
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files in `dir` whose names pass `wanted`, sorted by name, with
+/// `target` in its sorted place if it belongs there but does not exist yet
+fn config_fragments(dir: &Path, target: &Path, wanted: fn(&[u8]) -> bool) -> Result<Vec<PathBuf>> {
+    let mut fragments = Vec::new();
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry = entry.map_err(|e| io_error(dir, e))?;
+                if wanted(entry.file_name().as_bytes()) && !entry.file_type()?.is_dir() {
+                    fragments.push(entry.path());
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(io_error(dir, e)),
+    }
+
+    let new = target.parent() == Some(dir)
+        && target
+            .file_name()
+            .is_some_and(|name| wanted(name.as_bytes()));
+    if new && !fragments.iter().any(|path| path == target) {
+        fragments.push(target.to_path_buf());
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/// Read a configuration file, as root if the user may not, or `None` if it
+/// does not exist
+pub fn read_config_file(path: &Path, helper: &mut Helper) -> Result<Option<String>> {
+    let content = match fs::read(path) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            match helper.open(path, SymlinkPolicy::Follow)? {
+                Some(handle) => helper.read(&handle)?,
+                None => None,
+            }
+        }
+        Err(e) => return Err(io_error(path, e)),
+    };
+    Ok(content.map(|content| String::from_utf8_lossy(&content).into_owned()))
+}
+
+/// Which syntax a `SyntaxValidator` checks
+#[derive(Debug, Clone, Copy)]
+pub enum Syntax {
+    Json,
+    Toml,
+    Yaml,
+}
+
+/// Checks that a file parses as JSON, TOML or YAML
+pub struct SyntaxValidator(pub Syntax);
+
+impl Validator for SyntaxValidator {
+    fn name(&self) -> &str {
+        match self.0 {
+            Syntax::Json => "JSON",
+            Syntax::Toml => "TOML",
+            Syntax::Yaml => "YAML",
+        }
+    }
+
+    fn validate(
+        &self,
+        file: &Path,
+        _target: &Path,
+        _helper: &mut Helper,
+    ) -> Result<Option<Problem>> {
+        Ok(self.check(&fs::read_to_string(file)?))
+    }
+}
+
+impl SyntaxValidator {
+    /// The first syntax error in `content`, if any
+    fn check(&self, content: &str) -> Option<Problem> {
+        match self.0 {
+            Syntax::Json => serde_json::from_str::<serde_json::Value>(content)
+                .err()
+                .map(|e| Problem::at_line(e.to_string(), e.line())),
+            Syntax::Toml => content.parse::<toml::Table>().err().map(|e| {
+                let message = e.to_string().trim_end().to_string();
+                match e.span() {
+                    Some(span) => {
+                        let line = content[..span.start].matches('\n').count() + 1;
+                        Problem::at_line(message, line)
+                    }
+                    None => message.into(),
+                }
+            }),
+            Syntax::Yaml => yaml_rust2::YamlLoader::load_from_str(content)
+                .err()
+                .map(|e| Problem::at_line(e.to_string(), e.marker().line())),
+        }
+    }
+}
+
+/// Checks crontab syntax
+///
+/// System crontabs (`/etc/crontab`, `/etc/cron.d/*`) have a user field after
+/// the schedule, per-user crontabs do not.
+pub struct CrontabValidator {
+    pub system: bool,
+}
+
+const SPECIAL_SCHEDULES: [&str; 8] = [
+    "@reboot",
+    "@yearly",
+    "@annually",
+    "@monthly",
+    "@weekly",
+    "@daily",
+    "@midnight",
+    "@hourly",
+];
+
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl Validator for CrontabValidator {
+    fn name(&self) -> &str {
+        "crontab"
+    }
+
+    fn validate(
+        &self,
+        file: &Path,
+        _target: &Path,
+        _helper: &mut Helper,
+    ) -> Result<Option<Problem>> {
+        let content = fs::read_to_string(file)?;
+
+        for (index, line) in content.lines().enumerate() {
+            if let Err(msg) = self.check_line(line.trim()) {
+                let message = format!("line {}: {}", index + 1, msg);
+                return Ok(Some(Problem::at_line(message, index + 1)));
+            }
+        }
+
+        // cron ignores a last line without a newline
+        if !content.is_empty() && !content.ends_with('\n') {
+            let last = content.lines().count();
+            let message = "missing newline at end of file".to_string();
+            return Ok(Some(Problem::at_line(message, last)));
+        }
+
+        Ok(None)
+    }
+}
+
+impl CrontabValidator {
+    fn check_line(&self, line: &str) -> std::result::Result<(), String> {
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(());
+        }
+
+        // Environment settings such as `SHELL=/bin/sh` or `MAILTO = root`
+        if let Some((name, _)) = line.split_once('=') {
+            let name = name.trim();
+            if !name.is_empty() && !name.contains(char::is_whitespace) {
+                return Ok(());
+            }
+        }
+
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or("");
+
+        if first.starts_with('@') {
+            if !SPECIAL_SCHEDULES.contains(&first) {
+                return Err(format!("unknown schedule '{}'", first));
+            }
+        } else {
+            let limits: [(&str, u32, u32, &[&str]); 5] = [
+                ("minute", 0, 59, &[]),
+                ("hour", 0, 23, &[]),
+                ("day of month", 1, 31, &[]),
+                ("month", 1, 12, &MONTH_NAMES),
+                ("day of week", 0, 7, &DAY_NAMES),
+            ];
+            let mut field = Some(first);
+            for (name, min, max, names) in limits {
+                let value = field.ok_or_else(|| format!("missing {} field", name))?;
+                check_cron_field(value, min, max, names)
+                    .map_err(|msg| format!("bad {} '{}': {}", name, value, msg))?;
+                field = fields.next();
+            }
+            // `field` now holds the word after the schedule
+            if field.is_none() {
+                return Err("missing command".to_string());
+            }
+            if self.system && fields.next().is_none() {
+                return Err("missing user or command".to_string());
+            }
+            return Ok(());
+        }
+
+        let needed = if self.system { 2 } else { 1 };
+        if fields.count() < needed {
+            return Err("missing user or command".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Check one time field: `*`, numbers, names, ranges, lists and steps
+fn check_cron_field(
+    field: &str,
+    min: u32,
+    max: u32,
+    names: &[&str],
+) -> std::result::Result<(), String> {
+    let parse_value = |value: &str| -> std::result::Result<u32, String> {
+        if let Some(index) = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value))
+        {
+            // Month names are 1-based, day names 0-based
+            return Ok(index as u32 + min);
+        }
+        let number: u32 = value
+            .parse()
+            .map_err(|_| format!("'{}' is not a number", value))?;
+        if number < min || number > max {
+            return Err(format!("{} is out of range {}-{}", number, min, max));
+        }
+        Ok(number)
+    };
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+
+        if let Some(step) = step {
+            match step.parse::<u32>() {
+                Ok(step) if step > 0 => {}
+                _ => return Err(format!("bad step '{}'", step)),
+            }
+        }
+
+        if range == "*" {
+            continue;
+        }
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start = parse_value(start)?;
+                let end = parse_value(end)?;
+                if start > end {
+                    return Err(format!("range {}-{} is backwards", start, end));
+                }
+            }
+            None => {
+                parse_value(range)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps path patterns to the validator that checks them
+pub struct Registry {
+    entries: Vec<(String, Box<dyn Validator>)>,
+}
+
+impl Registry {
+    /// A registry holding the built-in validators
+    pub fn builtin() -> Registry {
+        let mut registry = Registry {
+            entries: Vec::new(),
+        };
+
+        registry.add(DOAS_CONF, DoasConfValidator);
+        registry.add("/etc/doas.d/*", DoasConfValidator);
+
+        let sudoers = ["visudo", "-c", "-q", "-f", "{}"];
+        registry.add("/etc/sudoers", CommandValidator::new("sudoers", &sudoers));
+        registry.add(
+            "/etc/sudoers.d/*",
+            CommandValidator::new("sudoers", &sudoers),
+        );
+
+        let sshd = ["sshd", "-t", "-f", "{}"];
+        registry.add("/etc/ssh/sshd_config", CommandValidator::new("sshd", &sshd));
+
+        registry.add(
+            "/etc/fstab",
+            CommandValidator::new("fstab", &["findmnt", "--verify", "--tab-file", "{}"]),
+        );
+
+        registry.add("/etc/crontab", CrontabValidator { system: true });
+        registry.add("/etc/cron.d/*", CrontabValidator { system: true });
+        registry.add("/var/spool/cron/*", CrontabValidator { system: false });
+        registry.add(
+            "/var/spool/cron/crontabs/*",
+            CrontabValidator { system: false },
+        );
+
+        registry.add("**/*.json", SyntaxValidator(Syntax::Json));
+        registry.add("**/*.toml", SyntaxValidator(Syntax::Toml));
+        registry.add("**/*.yaml", SyntaxValidator(Syntax::Yaml));
+        registry.add("**/*.yml", SyntaxValidator(Syntax::Yaml));
+
+        registry
+    }
+
+    /// Register a validator for paths matching `pattern`
+    pub fn add(&mut self, pattern: &str, validator: impl Validator + 'static) {
+        self.entries
+            .push((pattern.to_string(), Box::new(validator)));
+    }
+
+    /// A registry where the configured validators take precedence over the built-ins
+    pub fn with_config(validators: &[(String, Vec<String>)]) -> Registry {
+        let mut registry = Registry {
+            entries: Vec::new(),
+        };
+        for (pattern, template) in validators {
+            registry.add(pattern, CommandValidator::from_template(template.clone()));
+        }
+        registry.entries.extend(Registry::builtin().entries);
+        registry
+    }
+
+    /// The first validator whose pattern matches `path`
+    pub fn find(&self, path: &Path) -> Option<&dyn Validator> {
+        let path = path.to_string_lossy();
+        self.entries
+            .iter()
+            .find(|(pattern, _)| glob_match(pattern, &path))
+            .map(|(_, validator)| validator.as_ref())
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::builtin()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(path: &Path) -> Result<Option<String>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The files `assemble_doas_files` puts together in `dir`, by name
+    fn doas_files(dir: &Path, content: &str, target: &str) -> Vec<(Option<String>, String)> {
+        let name = |path: &Path| {
+            let path = path.strip_prefix(dir).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let files = assemble_doas_files(
+            &dir.join("doas.conf"),
+            &dir.join("doas.d"),
+            content.to_string(),
+            &dir.join(target),
+            &mut read,
+        )
+        .unwrap();
+        files
+            .into_iter()
+            .map(|(path, content)| (path.as_deref().map(name), content))
+            .collect()
+    }
+
+    #[test]
+    fn assemble_doas_config_reads_fragments_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let fragments = dir.path().join("doas.d");
+        fs::create_dir(&fragments).unwrap();
+        fs::create_dir(fragments.join("20-dir")).unwrap();
+        fs::write(dir.path().join("doas.conf"), "main").unwrap();
+        for name in ["30-c.conf", "10-a.conf", "20-b.conf", ".hidden.conf"] {
+            fs::write(fragments.join(name), name).unwrap();
+        }
+        let file = |path: &str| {
+            (
+                Some(path.to_string()),
+                path.rsplit('/').next().unwrap().to_string(),
+            )
+        };
+        let main = (Some("doas.conf".to_string()), "main".to_string());
+        let edited = (None, "edited".to_string());
+
+        // Editing the main file keeps the fragments after it
+        assert_eq!(
+            doas_files(dir.path(), "edited", "doas.conf"),
+            [
+                edited.clone(),
+                file("doas.d/10-a.conf"),
+                file("doas.d/20-b.conf"),
+                file("doas.d/30-c.conf"),
+            ]
+        );
+        // An existing fragment is replaced where it is
+        assert_eq!(
+            doas_files(dir.path(), "edited", "doas.d/20-b.conf"),
+            [
+                main.clone(),
+                file("doas.d/10-a.conf"),
+                edited.clone(),
+                file("doas.d/30-c.conf"),
+            ]
+        );
+        // A new fragment takes its sorted place
+        assert_eq!(
+            doas_files(dir.path(), "edited", "doas.d/15-new.conf"),
+            [
+                main.clone(),
+                file("doas.d/10-a.conf"),
+                edited.clone(),
+                file("doas.d/20-b.conf"),
+                file("doas.d/30-c.conf"),
+            ]
+        );
+        assert_eq!(
+            doas_files(dir.path(), "edited", "doas.d/99-new.conf").last(),
+            Some(&edited)
+        );
+        // Files doas does not read are judged on their own
+        assert_eq!(
+            doas_files(dir.path(), "edited", "doas.d/.hidden.conf"),
+            [(None, "edited".to_string())]
+        );
+        assert_eq!(doas_files(dir.path(), "edited", "hosts"), [edited]);
+    }
+
+    #[test]
+    fn assemble_doas_config_without_fragments() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            doas_files(dir.path(), "new", "doas.conf"),
+            [(None, "new".to_string())]
+        );
+        assert_eq!(
+            doas_files(dir.path(), "fragment", "doas.d/10-a.conf"),
+            [(None, "fragment".to_string())]
+        );
+    }
+
+    #[test]
+    fn finds_the_first_matching_validator() {
+        let builtin = Registry::builtin();
+        let name = |registry: &Registry, path: &str| {
+            registry
+                .find(Path::new(path))
+                .map(|validator| validator.name().to_string())
+        };
+        let cases = [
+            ("/etc/doas.conf", Some("doas.conf")),
+            ("/etc/doas.d/10-admins.conf", Some("doas.conf")),
+            ("/etc/sudoers", Some("sudoers")),
+            ("/etc/sudoers.d/alice", Some("sudoers")),
+            ("/etc/ssh/sshd_config", Some("sshd")),
+            ("/etc/cron.d/backup", Some("crontab")),
+            ("/var/spool/cron/crontabs/alice", Some("crontab")),
+            ("/etc/app/settings.json", Some("JSON")),
+            ("/etc/app/settings.toml", Some("TOML")),
+            ("/etc/app/compose.yml", Some("YAML")),
+            ("/etc/hosts", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(name(&builtin, path).as_deref(), expected, "{}", path);
+        }
+
+        // Configured validators come before the built-in ones, in file order
+        let words = |words: &str| words.split_whitespace().map(String::from).collect();
+        let configured = Registry::with_config(&[
+            ("/etc/app/*.json".to_string(), words("/usr/bin/jq . {}")),
+            ("/etc/app/**".to_string(), words("app-check {}")),
+            ("/etc/doas.conf".to_string(), words("doas-lint {}")),
+        ]);
+        let cases = [
+            ("/etc/app/settings.json", Some("jq")),
+            ("/etc/app/settings.toml", Some("app-check")),
+            ("/etc/doas.conf", Some("doas-lint")),
+            ("/etc/other.json", Some("JSON")),
+            ("/etc/sudoers", Some("sudoers")),
+            ("/etc/hosts", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(name(&configured, path).as_deref(), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_their_line() {
+        let line = |syntax, content: &str| {
+            SyntaxValidator(syntax)
+                .check(content)
+                .map(|problem| problem.line)
+        };
+        assert_eq!(line(Syntax::Json, "{\"a\": [1, 2]}\n"), None);
+        assert_eq!(
+            line(Syntax::Json, "{\n  \"a\": 1,\n  \"b\" 2\n}\n"),
+            Some(Some(3))
+        );
+        assert_eq!(line(Syntax::Toml, "[a]\nb = 1\n"), None);
+        assert_eq!(line(Syntax::Toml, "a = 1\n\nb = \n"), Some(Some(3)));
+        assert_eq!(line(Syntax::Toml, "a = 1\na = 2\n"), Some(Some(2)));
+        assert_eq!(line(Syntax::Yaml, "a:\n  - 1\n  - 2\n"), None);
+        assert_eq!(line(Syntax::Yaml, "a: 1\nb: [1, 2\nc: 3\n"), Some(Some(3)));
+    }
+
+    #[test]
+    fn finds_the_line_in_checker_output() {
+        let file = Path::new("/tmp/doasedit.x/edit/etc/ssh/sshd_config");
+        let line = |output: &str| Problem::from_output(output.to_string(), file).line;
+
+        assert_eq!(
+            line("/etc/sudoers: syntax error near line 12 <<<"),
+            Some(12)
+        );
+        assert_eq!(line("bad option on line 3."), Some(3));
+        assert_eq!(line("line 7: unknown keyword"), Some(7));
+        assert_eq!(
+            line("/tmp/doasedit.x/edit/etc/ssh/sshd_config:4: bad configuration option"),
+            Some(4)
+        );
+        assert_eq!(
+            line("/tmp/doasedit.x/edit/etc/ssh/sshd_config:9:5: unexpected token"),
+            Some(9)
+        );
+        // `line N` comes first, and only as a word of its own
+        assert_eq!(
+            line("/tmp/doasedit.x/edit/etc/ssh/sshd_config:4: bad line 6"),
+            Some(6)
+        );
+        assert_eq!(line("pipeline 3 failed"), None);
+        assert_eq!(line("deadline 5 passed at line 8"), Some(8));
+        // The file name must be followed by `N:`
+        assert_eq!(
+            line("/tmp/doasedit.x/edit/etc/ssh/sshd_config:22 is not a port"),
+            None
+        );
+        assert_eq!(
+            line("/tmp/doasedit.x/edit/etc/ssh/sshd_config.d:4: x"),
+            None
+        );
+        assert_eq!(line("line 0 is not a line"), None);
+        assert_eq!(line("no position at all"), None);
+    }
+
+    #[test]
+    fn assemble_sudoers_follows_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("sudoers");
+        let fragments = dir.path().join("sudoers.d");
+        fs::create_dir(&fragments).unwrap();
+        fs::write(
+            &main,
+            "main\n@include local\n#includedir sudoers.d\n@include missing\nend\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("local"), "local").unwrap();
+        fs::write(fragments.join("b"), "b\n").unwrap();
+        fs::write(fragments.join("a"), "a\n").unwrap();
+        fs::write(fragments.join("c.bak"), "backup\n").unwrap();
+        fs::write(fragments.join("c~"), "backup\n").unwrap();
+        let assemble = |content: &str, target: &Path| {
+            assemble_sudoers(&main, content.to_string(), target, &mut read).unwrap()
+        };
+
+        assert_eq!(assemble("x", &main), "x\n");
+        assert_eq!(
+            assemble("edited", &fragments.join("b")),
+            "main\nlocal\na\nedited\nend\n"
+        );
+        assert_eq!(
+            assemble("new", &fragments.join("aa")),
+            "main\nlocal\na\nnew\nb\nend\n"
+        );
+        // Files sudo does not read are judged on their own
+        assert_eq!(assemble("alone", &fragments.join("a.bak")), "alone");
+        assert_eq!(assemble("alone", &dir.path().join("other")), "alone");
+    }
+
+    #[test]
+    fn assemble_sudoers_stops_include_loops() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("sudoers");
+        fs::write(&main, "@include sudoers\n").unwrap();
+        let target = dir.path().join("other");
+        let assembled = assemble_sudoers(&main, "x".to_string(), &target, &mut read).unwrap();
+        assert_eq!(assembled, "x");
+    }
+}